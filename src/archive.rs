@@ -0,0 +1,212 @@
+use std::path::Path;
+
+use miette::{Context, IntoDiagnostic};
+use rusqlite::{Connection, params};
+
+use crate::{
+    client,
+    manifest::{Entry, Status},
+    model::{Message, MessageAttachment},
+};
+
+/// The tables of an archive. Messages are keyed by id, so writing a message
+/// again replaces it rather than adding another.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS messages (
+        id TEXT PRIMARY KEY,
+        -- the group id, or dm_<other user id> for direct messages
+        group_id TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        -- the sender's nickname
+        user TEXT NOT NULL,
+        -- seconds since the Unix epoch
+        created_at INTEGER NOT NULL,
+        text TEXT
+    );
+    CREATE TABLE IF NOT EXISTS attachments (
+        message_id TEXT NOT NULL REFERENCES messages (id),
+        -- the attachment's index within its message
+        idx INTEGER NOT NULL,
+        type TEXT NOT NULL,
+        url TEXT,
+        -- where the attachment was saved, once downloaded
+        local_path TEXT,
+        PRIMARY KEY (message_id, idx)
+    );
+";
+
+/// A SQLite database of the messages downloaded from, and their attachments.
+pub struct Archive {
+    conn: Connection,
+}
+
+impl Archive {
+    /// Open the archive at `path`, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> miette::Result<Self> {
+        let conn = Connection::open(path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Unable to open database {path:?}"))?;
+        conn.execute_batch(SCHEMA).into_diagnostic()?;
+        Ok(Self { conn })
+    }
+
+    /// Add (or update) `message`, sent by `user` in the conversation `group_id`, along
+    /// with its attachments. Where the attachments were saved is kept.
+    pub fn write_message(
+        &mut self,
+        message: &Message,
+        group_id: &str,
+        user: &str,
+    ) -> miette::Result<()> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        tx.execute(
+            "INSERT INTO messages (id, group_id, user_id, user, created_at, text)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (id) DO UPDATE SET
+                group_id = excluded.group_id, user_id = excluded.user_id, user = excluded.user,
+                created_at = excluded.created_at, text = excluded.text",
+            params![
+                message.id,
+                group_id,
+                message.user_id,
+                user,
+                message.created_at.timestamp(),
+                message.text,
+            ],
+        )
+        .into_diagnostic()?;
+        for (index, attachment) in message.attachments.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO attachments (message_id, idx, type, url) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (message_id, idx) DO UPDATE SET
+                    type = excluded.type, url = excluded.url",
+                params![
+                    message.id,
+                    index as i64,
+                    attachment_type(attachment),
+                    attachment_url(attachment, group_id),
+                ],
+            )
+            .into_diagnostic()?;
+        }
+        tx.commit().into_diagnostic()
+    }
+
+    /// Record where the attachments of the `entries` of a download run were saved.
+    pub fn write_paths(&mut self, entries: &[Entry]) -> miette::Result<()> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        for entry in entries {
+            let Some(path) = &entry.path else {
+                continue;
+            };
+            // none were saved where the path says
+            if matches!(
+                entry.status,
+                Status::Failed | Status::TooLarge | Status::Unavailable | Status::NotFetched
+            ) {
+                continue;
+            }
+            // a resized image's url extends the attachment's, and a video's preview
+            // image has another url altogether, so isn't mistaken for the video
+            tx.execute(
+                "UPDATE attachments SET local_path = ?3
+                 WHERE message_id = ?1 AND instr(?2, url) = 1",
+                params![entry.message_id, entry.url, path.to_string_lossy()],
+            )
+            .into_diagnostic()?;
+        }
+        tx.commit().into_diagnostic()
+    }
+}
+
+/// The `type` of `attachment`, as GroupMe names it.
+fn attachment_type(attachment: &MessageAttachment) -> String {
+    match serde_json::to_value(attachment) {
+        Ok(serde_json::Value::Object(fields)) => fields
+            .get("type")
+            .and_then(|value| value.as_str())
+            .unwrap_or("unknown")
+            .to_string(),
+        _ => "unknown".to_string(),
+    }
+}
+
+/// The url `attachment` is downloaded from, as recorded in the manifest, if it has one.
+fn attachment_url(attachment: &MessageAttachment, group_id: &str) -> Option<String> {
+    match attachment {
+        MessageAttachment::File { file_id } => Some(client::file_url(group_id, file_id)),
+        MessageAttachment::Location { lat, lon, .. } => Some(format!("geo:{lat},{lon}")),
+        _ => attachment
+            .get_download_url_and_ext()
+            .map(|(url, _)| url.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    fn message(text: &str) -> Message {
+        Message {
+            id: "1".to_string(),
+            source_guid: "1".to_string(),
+            created_at: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            user_id: "2".to_string(),
+            group_id: Some("3".to_string()),
+            recipient_id: None,
+            name: "user".to_string(),
+            avatar_url: None,
+            text: Some(text.to_string()),
+            system: false,
+            favorited_by: vec![],
+            attachments: vec![MessageAttachment::Image {
+                url: "https://i.groupme.com/1x1.png.abc".to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn writing_a_message_again_updates_it_and_keeps_paths() {
+        let mut archive = Archive {
+            conn: Connection::open_in_memory().unwrap(),
+        };
+        archive.conn.execute_batch(SCHEMA).unwrap();
+
+        archive.write_message(&message("hi"), "3", "alice").unwrap();
+        archive
+            .write_paths(&[Entry {
+                group: "group".to_string(),
+                message_id: "1".to_string(),
+                created_at: None,
+                user: "alice".to_string(),
+                url: "https://i.groupme.com/1x1.png.abc.large".to_string(),
+                path: Some("photo.png".into()),
+                status: Status::Downloaded,
+                size: Some(1),
+                mislabeled_ext: None,
+                error: None,
+            }])
+            .unwrap();
+        archive
+            .write_message(&message("edited"), "3", "alice")
+            .unwrap();
+
+        let (count, text): (i64, String) = archive
+            .conn
+            .query_row("SELECT count(*), text FROM messages", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!((count, text.as_str()), (1, "edited"));
+
+        let (kind, path): (String, String) = archive
+            .conn
+            .query_row("SELECT type, local_path FROM attachments", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!((kind.as_str(), path.as_str()), ("image", "photo.png"));
+    }
+}
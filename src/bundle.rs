@@ -0,0 +1,227 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Datelike, Local, Timelike};
+use miette::IntoDiagnostic;
+use zip::{CompressionMethod, ZipArchive, ZipWriter, write::SimpleFileOptions};
+
+use crate::{failures::FAILURES_FILENAME, manifest::MANIFEST_FILENAME};
+
+/// Extensions of files which are compressed already, so are stored as they are.
+const COMPRESSED_EXTS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "heif", "mp4", "mov", "webm",
+];
+
+/// The directory a download into the zip file at `zip_path` is staged in,
+/// next to it, before being written to it by [`write_zip`].
+pub fn staging_dir(zip_path: &Path) -> PathBuf {
+    let filename = zip_path.file_name().unwrap_or_default().to_string_lossy();
+    zip_path.with_file_name(format!(".{filename}.parts"))
+}
+
+/// Move the files in `dir` into a zip file at `zip_path`, under their paths relative
+/// to `dir`. The entries of an existing zip file are kept, unless a file of the same
+/// path replaces them, so that downloads can be added to it over several runs.
+///
+/// The zip file is written next to `zip_path` then renamed over it, and the files are
+/// only removed from `dir` once it is, so that nothing is lost if writing it fails.
+/// Manifests and failures are left in `dir` rather than moved into the zip file, so
+/// that `retry-failures` can still be pointed at it. Returns the number of files
+/// written.
+pub fn write_zip(dir: &Path, zip_path: &Path) -> miette::Result<usize> {
+    let mut files = Vec::new();
+    collect_files(dir, &mut files)?;
+    files.retain(|path| !is_state_file(path));
+    // so that the same files make the same zip
+    files.sort();
+    let names = files
+        .iter()
+        .map(|path| entry_name(dir, path))
+        .collect::<miette::Result<Vec<_>>>()?;
+
+    let filename = zip_path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = zip_path.with_file_name(format!(".{filename}.tmp"));
+    let mut zip = ZipWriter::new(BufWriter::new(File::create(&temp_path).into_diagnostic()?));
+
+    if fs::exists(zip_path).into_diagnostic()? {
+        let mut existing =
+            ZipArchive::new(File::open(zip_path).into_diagnostic()?).into_diagnostic()?;
+        for index in 0..existing.len() {
+            let entry = existing.by_index_raw(index).into_diagnostic()?;
+            if !names.iter().any(|name| name == entry.name()) {
+                zip.raw_copy_file(entry).into_diagnostic()?;
+            }
+        }
+    }
+
+    for (path, name) in files.iter().zip(names) {
+        let compressed = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| COMPRESSED_EXTS.contains(&ext.to_ascii_lowercase().as_str()));
+        let metadata = fs::metadata(path).into_diagnostic()?;
+        let mut options = SimpleFileOptions::default()
+            .compression_method(if compressed {
+                CompressionMethod::Stored
+            } else {
+                CompressionMethod::Deflated
+            })
+            .large_file(metadata.len() >= u32::MAX as u64);
+        // keep the time the message was sent, which downloads are dated by
+        if let Some(modified) = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| zip_time(modified.into()))
+        {
+            options = options.last_modified_time(modified);
+        }
+
+        zip.start_file(name, options).into_diagnostic()?;
+        io::copy(&mut File::open(path).into_diagnostic()?, &mut zip).into_diagnostic()?;
+    }
+    zip.finish()
+        .into_diagnostic()?
+        .into_inner()
+        .map_err(|err| err.into_error())
+        .into_diagnostic()?
+        .sync_all()
+        .into_diagnostic()?;
+    fs::rename(&temp_path, zip_path).into_diagnostic()?;
+
+    for path in &files {
+        fs::remove_file(path).into_diagnostic()?;
+    }
+    remove_empty_dirs(dir)?;
+    Ok(files.len())
+}
+
+/// Whether the file at `path` records the state of a download, so is left out of zip files.
+fn is_state_file(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name == MANIFEST_FILENAME || name == FAILURES_FILENAME)
+}
+
+/// The name of the file at `path` in a zip file of `dir`.
+fn entry_name(dir: &Path, path: &Path) -> miette::Result<String> {
+    Ok(path
+        .strip_prefix(dir)
+        .into_diagnostic()?
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/"))
+}
+
+/// Remove `dir` and its subdirectories, if they hold no files.
+fn remove_empty_dirs(dir: &Path) -> miette::Result<()> {
+    for entry in fs::read_dir(dir).into_diagnostic()? {
+        let entry = entry.into_diagnostic()?;
+        if entry.file_type().into_diagnostic()?.is_dir() {
+            remove_empty_dirs(&entry.path())?;
+        }
+    }
+    if fs::read_dir(dir).into_diagnostic()?.next().is_none() {
+        fs::remove_dir(dir).into_diagnostic()?;
+    }
+    Ok(())
+}
+
+/// Add the paths of the files in `dir` and its subdirectories to `files`.
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> miette::Result<()> {
+    for entry in fs::read_dir(dir).into_diagnostic()? {
+        let entry = entry.into_diagnostic()?;
+        let file_type = entry.file_type().into_diagnostic()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// `time` as a zip file's modification time, if it is within the years zip files can hold.
+fn zip_time(time: DateTime<Local>) -> Option<zip::DateTime> {
+    zip::DateTime::from_date_and_time(
+        u16::try_from(time.year()).ok()?,
+        time.month() as u8,
+        time.day() as u8,
+        time.hour() as u8,
+        time.minute() as u8,
+        time.second() as u8,
+    )
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn write_zip_moves_files_in_with_relative_paths() {
+        let dir = std::env::temp_dir().join(format!("bundle-test-{}", std::process::id()));
+        let staged = dir.join("staged");
+        fs::create_dir_all(staged.join("group")).unwrap();
+        fs::write(staged.join("group/image.jpg"), b"jpeg").unwrap();
+        fs::write(staged.join("group/notes.txt"), b"text").unwrap();
+        let zip_path = dir.join("out.zip");
+
+        assert_eq!(write_zip(&staged, &zip_path).unwrap(), 2);
+
+        assert!(!staged.exists());
+        let mut zip = ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        let mut names = zip.file_names().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["group/image.jpg", "group/notes.txt"]);
+        let mut content = String::new();
+        zip.by_name("group/image.jpg")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "jpeg");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_zip_keeps_existing_entries_and_state_files() {
+        let dir = std::env::temp_dir().join(format!("bundle-merge-test-{}", std::process::id()));
+        let staged = dir.join("staged");
+        let zip_path = dir.join("out.zip");
+        fs::create_dir_all(staged.join("group")).unwrap();
+        fs::write(staged.join("group/old.jpg"), b"old").unwrap();
+        fs::write(staged.join("group/replaced.jpg"), b"before").unwrap();
+        write_zip(&staged, &zip_path).unwrap();
+
+        fs::create_dir_all(staged.join("group")).unwrap();
+        fs::write(staged.join("group/new.jpg"), b"new").unwrap();
+        fs::write(staged.join("group/replaced.jpg"), b"after").unwrap();
+        fs::write(staged.join("group").join(MANIFEST_FILENAME), b"[]").unwrap();
+        fs::write(staged.join(FAILURES_FILENAME), b"[]").unwrap();
+        assert_eq!(write_zip(&staged, &zip_path).unwrap(), 2);
+
+        let mut zip = ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        let mut names = zip.file_names().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            ["group/new.jpg", "group/old.jpg", "group/replaced.jpg"]
+        );
+        let mut content = String::new();
+        zip.by_name("group/replaced.jpg")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "after");
+        assert!(staged.join("group").join(MANIFEST_FILENAME).exists());
+        assert!(staged.join(FAILURES_FILENAME).exists());
+        assert!(!staged.join("group/new.jpg").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    ffi::OsString,
     fs::{self, File},
     path::{Path, PathBuf},
 };
@@ -6,107 +7,278 @@ use std::{
 #[cfg(not(windows))]
 use std::os::unix::fs::PermissionsExt;
 
-use miette::IntoDiagnostic;
 use serde::{Deserialize, Serialize};
 
-use crate::config::Config;
+use crate::{
+    config::Config,
+    error::{Error, Result},
+    token_store,
+};
 
 /// A helper for caching [`Config`] and frequently used items.
 #[derive(Clone)]
 pub struct Cache {
     cache_dir: PathBuf,
     config_dir: PathBuf,
+    /// the profile whose config and cached items are used, the default one if `None`
+    profile: Option<String>,
+    /// the config file to use instead of the profile's one in `config_dir`
+    config_file: Option<PathBuf>,
 }
 
 impl Cache {
-    /// Create a [`Cache`] ensuring that necessary directories are created,
-    /// and that we can write files to them/remove files from them.
-    pub fn new() -> miette::Result<Self> {
+    /// Create a [`Cache`] for the given `profile` (the default one if `None`),
+    /// ensuring that necessary directories are created, and that we can write
+    /// files to them/remove files from them. With a `config_file`, the config is
+    /// kept in that file instead, and the platform's config directory isn't used.
+    pub fn new(profile: Option<String>, config_file: Option<PathBuf>) -> Result<Self> {
         const APP_DIRNAME: &str = "groupme_downloader";
 
         let cache_dir = dirs::cache_dir()
             .map(|dir| dir.join(APP_DIRNAME))
-            .ok_or_else(|| miette::miette!("Unable to locate user's cache directory."))?;
+            .ok_or(Error::DirNotFound { kind: "cache" })?;
 
-        let config_dir = dirs::config_dir()
-            .map(|dir| dir.join(APP_DIRNAME))
-            .ok_or_else(|| miette::miette!("Unable to locate user's config directory."))?;
+        let config_dir = match &config_file {
+            // a bare file name is in the current directory
+            Some(config_file) => match config_file.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                _ => PathBuf::from("."),
+            },
+            None => dirs::config_dir()
+                .map(|dir| dir.join(APP_DIRNAME))
+                .ok_or(Error::DirNotFound { kind: "config" })?,
+        };
 
         for dir in [&cache_dir, &config_dir] {
-            if !fs::exists(dir).into_diagnostic()? {
-                std::fs::create_dir(dir).into_diagnostic()?;
+            if !fs::exists(dir)? {
+                std::fs::create_dir_all(dir)?;
             }
-            let test_file = dir.join(".test_file");
-            std::fs::write(&test_file, "").into_diagnostic()?;
-            std::fs::remove_file(test_file).into_diagnostic()?;
+            check_writable(dir)?;
         }
 
         Ok(Self {
             cache_dir,
             config_dir,
+            profile,
+            config_file,
         })
     }
 
+    /// Create a [`Cache`] within `dir`, without touching the filesystem: cached items
+    /// are kept in its `cache` directory, which must be created before any are written,
+    /// and the config in its `config` directory. Useful to keep tests apart from the
+    /// user's own cache.
+    pub fn in_dir(dir: &Path) -> Self {
+        Self {
+            cache_dir: dir.join("cache"),
+            config_dir: dir.join("config"),
+            profile: None,
+            config_file: None,
+        }
+    }
+
+    /// The directory cached items are kept in.
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    /// The directory the config file is kept in.
+    pub fn config_dir(&self) -> &Path {
+        &self.config_dir
+    }
+
     // -- config
 
-    fn config_file_path(&self) -> PathBuf {
+    /// The file the [`Config`] is persisted to.
+    pub fn config_file_path(&self) -> PathBuf {
         const APP_CONFIG_FILENAME: &str = "config.json";
-        self.config_dir.join(APP_CONFIG_FILENAME)
+        if let Some(config_file) = &self.config_file {
+            return config_file.clone();
+        }
+        match &self.profile {
+            Some(profile) => self.config_dir.join(format!("config.{profile}.json")),
+            None => self.config_dir.join(APP_CONFIG_FILENAME),
+        }
     }
 
     /// Get the [`Config`] from disk, if one exists.
     /// To persist any config changes to disk, use [`Self::write_config`].
-    pub fn read_config(&self) -> miette::Result<Option<Config>> {
+    /// The API token is read from the OS keyring if that is where it was stored.
+    pub fn read_config(&self) -> Result<Option<Config>> {
         let filepath = &self.config_file_path();
-        read_json(filepath)
+        let Some(config) = read_json::<Config>(filepath)? else {
+            return Ok(None);
+        };
+
+        if !config.api_token_in_keyring {
+            return Ok(Some(config));
+        }
+        let api_token = token_store::load_token(self.profile.as_deref())?;
+        Ok(Some(Config {
+            api_token: api_token.into(),
+            ..config
+        }))
     }
 
     /// Persist the [`Config`] to disk, and ensures the correct file mode is set.
-    pub fn write_config(&self, config: &Config) -> miette::Result<()> {
+    /// The API token is stored in the OS keyring when possible, and in the
+    /// config file otherwise. A config file given explicitly keeps its token, so
+    /// that it works wherever it is copied to, and apart from other config files.
+    pub fn write_config(&self, config: &Config) -> Result<()> {
         let filepath = &self.config_file_path();
-        write_json(filepath, config)
+
+        if self.config_file.is_none()
+            && token_store::store_token(self.profile.as_deref(), config.api_token.expose())
+        {
+            let config = Config {
+                api_token: Default::default(),
+                api_token_in_keyring: true,
+                ..config.clone()
+            };
+            write_json(filepath, &config)
+        } else {
+            let config = Config {
+                api_token_in_keyring: false,
+                ..config.clone()
+            };
+            write_json(filepath, &config)?;
+            #[cfg(windows)]
+            restrict_to_current_user(filepath);
+            Ok(())
+        }
     }
 
     // -- cache
 
+    /// Where a cached file is kept, each profile having its own copy.
+    fn cache_file_path(&self, filename: &Path) -> PathBuf {
+        match &self.profile {
+            Some(profile) => {
+                let mut prefixed = OsString::from(format!("{profile}."));
+                prefixed.push(filename);
+                self.cache_dir.join(prefixed)
+            }
+            None => self.cache_dir.join(filename),
+        }
+    }
+
     /// Read a cached file as JSON, if it exists.
-    pub fn read_cache_item<T>(&self, filename: impl AsRef<Path>) -> miette::Result<Option<T>>
+    pub fn read_cache_item<T>(&self, filename: impl AsRef<Path>) -> Result<Option<T>>
     where
         for<'de> T: Deserialize<'de>,
     {
-        let filepath = &self.cache_dir.join(filename.as_ref());
+        let filepath = &self.cache_file_path(filename.as_ref());
         read_json(filepath)
     }
 
+    /// The name a file in the cache directory named `filename` is cached under, if
+    /// it is one of this profile's rather than another profile's.
+    fn cache_item_name<'a>(&self, filename: &'a str) -> Option<&'a str> {
+        match &self.profile {
+            Some(profile) => filename.strip_prefix(profile.as_str())?.strip_prefix('.'),
+            // profiles' files have their profile and a dot in front of the name
+            None => (filename.matches('.').count() == 1).then_some(filename),
+        }
+    }
+
+    /// Remove this profile's cached files for which `remove` returns true given the
+    /// name they are cached under, returning how many were removed.
+    pub fn remove_cache_items(&self, remove: impl Fn(&str) -> bool) -> Result<usize> {
+        if !fs::exists(&self.cache_dir)? {
+            return Ok(0);
+        }
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.cache_dir)? {
+            let entry = entry?;
+            let filename = entry.file_name();
+            let filename = filename.to_string_lossy();
+            if !entry.file_type()?.is_file()
+                || !self.cache_item_name(&filename).is_some_and(&remove)
+            {
+                continue;
+            }
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Remove a cached file, if it exists.
+    pub fn remove_cache_item(&self, filename: impl AsRef<Path>) -> Result<()> {
+        let filepath = &self.cache_file_path(filename.as_ref());
+        match fs::remove_file(filepath) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
     /// Write a file to the cache directory, overwriting it if it exists.
-    pub fn write_cache_item<T>(&self, filename: impl AsRef<Path>, data: &T) -> miette::Result<()>
+    pub fn write_cache_item<T>(&self, filename: impl AsRef<Path>, data: &T) -> Result<()>
     where
         T: Serialize,
     {
-        let filepath = &self.cache_dir.join(filename.as_ref());
+        let filepath = &self.cache_file_path(filename.as_ref());
         write_json(filepath, data)
     }
 }
 
+/// Check that we can write files to, and remove files from, `dir`.
+pub fn check_writable(dir: &Path) -> Result<()> {
+    let test_file = dir.join(".test_file");
+    std::fs::write(&test_file, "")
+        .and_then(|_| std::fs::remove_file(test_file))
+        .map_err(|source| Error::DirNotWritable {
+            dir: dir.to_path_buf(),
+            source,
+        })
+}
+
+/// Restrict the file at `filepath` to the current user, as its mode does on other
+/// platforms, since it holds the API token. Windows has no file modes, so the
+/// permissions the file inherited are replaced with `icacls` instead.
+#[cfg(windows)]
+fn restrict_to_current_user(filepath: &Path) {
+    let result = std::env::var("USERNAME")
+        .map_err(|err| err.to_string())
+        .and_then(|user| {
+            let output = std::process::Command::new("icacls")
+                .arg(filepath)
+                .args(["/inheritance:r", "/grant:r"])
+                .arg(format!("{user}:F"))
+                .output()
+                .map_err(|err| err.to_string())?;
+            if output.status.success() {
+                return Ok(());
+            }
+            // icacls reports errors on stdout
+            let message = [output.stdout, output.stderr].concat();
+            Err(String::from_utf8_lossy(&message).trim().to_string())
+        });
+    if let Err(err) = result {
+        tracing::warn!(
+            "unable to restrict {filepath:?} to your user, other users of this computer may be able to read your API token: {err}"
+        );
+    }
+}
+
 /// Read JSON from a file and deserialize as `T`, if the file exists.
-fn read_json<T>(filepath: &PathBuf) -> miette::Result<Option<T>>
+fn read_json<T>(filepath: &PathBuf) -> Result<Option<T>>
 where
     for<'de> T: Deserialize<'de>,
 {
-    if !fs::exists(filepath).into_diagnostic()? {
+    if !fs::exists(filepath)? {
         return Ok(None);
     }
 
-    let reader = File::open(filepath).into_diagnostic()?;
-    let data: Result<T, _> =
-        serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_reader(reader))
-            .into_diagnostic();
+    let reader = File::open(filepath)?;
+    let data: T =
+        serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_reader(reader))?;
 
-    Some(data).transpose()
+    Ok(Some(data))
 }
 
 /// Write `data` as JSON to a file, overwriting if the file exists.
-fn write_json<T>(filepath: &PathBuf, data: &T) -> miette::Result<()>
+fn write_json<T>(filepath: &PathBuf, data: &T) -> Result<()>
 where
     T: Serialize,
 {
@@ -114,16 +286,56 @@ where
         .create(true)
         .write(true)
         .truncate(true)
-        .open(filepath)
-        .into_diagnostic()?;
+        .open(filepath)?;
 
-    let mut permissions = file.metadata().into_diagnostic()?.permissions();
+    let mut permissions = file.metadata()?.permissions();
 
     #[cfg(not(windows))]
     permissions.set_mode(0o600);
 
-    fs::set_permissions(filepath, permissions).into_diagnostic()?;
+    fs::set_permissions(filepath, permissions)?;
+
+    serde_path_to_error::serialize(data, &mut serde_json::Serializer::pretty(file))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    serde_path_to_error::serialize(data, &mut serde_json::Serializer::pretty(file))
-        .into_diagnostic()
+    #[test]
+    fn remove_cache_items_only_removes_the_profiles_files() {
+        let dir = std::env::temp_dir().join(format!("cache-test-{}", std::process::id()));
+        let default = Cache::in_dir(&dir);
+        let work = Cache {
+            profile: Some("work".to_string()),
+            ..Cache::in_dir(&dir)
+        };
+        fs::create_dir_all(default.cache_dir()).unwrap();
+        for filename in [
+            "groups.json",
+            "hashes.json",
+            "work.groups.json",
+            "home.groups.json",
+        ] {
+            fs::write(default.cache_dir().join(filename), b"[]").unwrap();
+        }
+
+        assert_eq!(work.remove_cache_items(|_| true).unwrap(), 1);
+        assert_eq!(
+            default
+                .remove_cache_items(|filename| filename != "hashes.json")
+                .unwrap(),
+            1
+        );
+
+        let mut remaining = fs::read_dir(default.cache_dir())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        remaining.sort();
+        assert_eq!(remaining, ["hashes.json", "home.groups.json"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -1,103 +1,474 @@
-use std::{fmt::Display, time::Duration};
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
 use futures_core::Stream;
-use miette::IntoDiagnostic;
-use serde::Deserialize;
+use futures_util::StreamExt;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::{Instrument, debug, debug_span, warn};
+use url::Url;
 
 use crate::{
     cache::Cache,
     config::Config,
-    model::{Group, GroupMessagesResponse, GroupsResponse, Message},
+    error::{ApiDetails, Error, Result},
+    model::{
+        Chat, ChatsResponse, DirectMessagesResponse, ErrorResponse, FileData, FileDataResponse,
+        Group, GroupMessagesPage, GroupMessagesResponse, GroupsResponse, Message, Timestamp, User,
+        UserResponse,
+    },
+    rate_limit::RateLimiter,
+    retry::{self, Failure},
+    transport::{HttpResponse, HttpTransport, ReqwestTransport},
 };
 
+/// How long to wait for a server before giving up on a request (and retrying it),
+/// unless configured otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The `User-Agent` requests are sent with, unless configured otherwise.
+pub const DEFAULT_USER_AGENT: &str = concat!("groupme-downloader/", env!("CARGO_PKG_VERSION"));
+
+/// How long to wait between API requests, such as for pages of messages, unless
+/// configured otherwise. Rate limiting is handled by retrying 429 responses, so this can be small.
+pub const DEFAULT_PAGE_DELAY: Duration = Duration::from_millis(250);
+
+/// The maximum page size allowed by the `/groups/:id/messages` endpoint, which
+/// is also used unless configured otherwise.
+pub const MAX_MESSAGES_PER_PAGE: usize = 100;
+
+/// GroupMe's file service, which hosts File attachments.
+pub const FILE_API_BASE_URL: &str = "https://file.groupme.com/v1";
+
+/// The header the file service takes the API token in.
+const ACCESS_TOKEN_HEADER: &str = "X-Access-Token";
+
+/// The group list as persisted in the [`Cache`].
+#[derive(Deserialize, Serialize)]
+struct CachedGroups {
+    fetched_at: Timestamp,
+    groups: Vec<Group>,
+}
+
 #[derive(Clone)]
-pub struct Client {
-    #[expect(dead_code)]
+pub struct Client<H = ReqwestTransport> {
     cache: Cache,
     config: Config,
+    transport: H,
+    http: reqwest::Client,
+    max_retries: u32,
+    /// how many messages of a group to fetch per page
+    messages_per_page: usize,
+    /// spaces out API requests, shared by clones so that downloading from several
+    /// groups at once doesn't add up to more requests than one would
+    limiter: RateLimiter,
 }
 
 impl Client {
-    /// Instantiate a [`Client`].
-    pub fn new(cache: Cache, config: Config) -> Client {
-        Self { cache, config }
+    /// Instantiate a [`Client`] whose requests give up when connecting, or waiting
+    /// for more of a response, takes longer than `timeout`. The whole of a response
+    /// isn't limited, as large videos can legitimately take longer than that.
+    /// Requests are sent with the `user_agent` if given, otherwise the configured one.
+    pub fn new(
+        cache: Cache,
+        config: Config,
+        timeout: Duration,
+        user_agent: Option<&str>,
+    ) -> Result<Client> {
+        let user_agent = user_agent
+            .or(config.user_agent.as_deref())
+            .unwrap_or(DEFAULT_USER_AGENT);
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(timeout)
+            .read_timeout(timeout)
+            .user_agent(user_agent);
+        // without one configured, reqwest uses the proxy environment variables
+        if let Some(proxy) = &config.proxy {
+            let proxy = reqwest::Proxy::all(proxy).map_err(|source| Error::InvalidProxy {
+                proxy: proxy.clone(),
+                source,
+            })?;
+            builder = builder.proxy(proxy);
+        }
+        let http = builder.build()?;
+        Ok(Self::with_transport(
+            cache,
+            config,
+            ReqwestTransport::new(http.clone()),
+            http,
+        ))
     }
+}
 
-    /// TODO: only gets the first 100.
-    pub async fn get_all_groups(&self) -> miette::Result<Vec<Group>> {
-        let mut groups = Vec::new();
-        let mut page = 1;
+impl<H: HttpTransport> Client<H> {
+    /// Instantiate a [`Client`] making API requests through `transport`, and
+    /// other requests through `http`.
+    pub fn with_transport(
+        cache: Cache,
+        config: Config,
+        transport: H,
+        http: reqwest::Client,
+    ) -> Self {
+        Self {
+            cache,
+            config,
+            transport,
+            http,
+            max_retries: retry::DEFAULT_MAX_RETRIES,
+            messages_per_page: MAX_MESSAGES_PER_PAGE,
+            limiter: RateLimiter::new(DEFAULT_PAGE_DELAY),
+        }
+    }
 
-        loop {
+    /// Set how long to wait between API requests, such as for pages of messages,
+    /// give or take some jitter.
+    pub fn with_page_delay(self, page_delay: Duration) -> Self {
+        Self {
+            limiter: RateLimiter::new(page_delay),
+            ..self
+        }
+    }
+
+    /// Set how many messages of a group to fetch per page, up to [`MAX_MESSAGES_PER_PAGE`].
+    /// A larger page size is lowered to that, with a warning.
+    pub fn with_messages_per_page(self, messages_per_page: usize) -> Self {
+        if messages_per_page > MAX_MESSAGES_PER_PAGE {
+            warn!(
+                "GroupMe returns at most {MAX_MESSAGES_PER_PAGE} messages per page, using that instead of {messages_per_page}"
+            );
+        }
+        Self {
+            messages_per_page: messages_per_page.clamp(1, MAX_MESSAGES_PER_PAGE),
+            ..self
+        }
+    }
+
+    /// Set how many times a failed request is retried.
+    pub fn with_max_retries(self, max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            ..self
+        }
+    }
+
+    /// The cache this client keeps the group list in.
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    /// How many times a failed request is retried.
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    /// Start a request to download an attachment from `url`. File attachments are
+    /// only served to group members, so requests to the file service carry the API token.
+    pub fn download_request(&self, url: &str) -> reqwest::RequestBuilder {
+        let request = self.http.get(url);
+        if url.starts_with(FILE_API_BASE_URL) {
+            request.header(ACCESS_TOKEN_HEADER, self.config.api_token.expose())
+        } else {
+            request
+        }
+    }
+
+    /// Look up the name and type of a File attachment shared in a group.
+    pub async fn get_file_data(&self, group_id: &str, file_id: &str) -> Result<FileData> {
+        let url = format!("{FILE_API_BASE_URL}/{group_id}/fileData");
+        let files = retry::with_retries(self.max_retries, || async {
+            let response = self
+                .http
+                .post(&url)
+                .header(ACCESS_TOKEN_HEADER, self.config.api_token.expose())
+                .json(&serde_json::json!({ "file_ids": [file_id] }))
+                .send()
+                .await
+                .map_err(Failure::from_reqwest)?;
+
+            let status = response.status();
+            let headers = response.headers().clone();
+            let body = response.bytes().await.map_err(Failure::from_reqwest)?;
+            debug!("POST /{group_id}/fileData {status}");
+            if !status.is_success() {
+                let error = status_error(status, &body);
+                return Err(Failure::from_status(status, &headers, error));
+            }
+
+            Ok(serde_path_to_error::deserialize::<_, FileDataResponse>(
+                &mut serde_json::Deserializer::from_slice(&body),
+            )?)
+        })
+        .await?;
+
+        files
+            .into_iter()
+            .next()
+            .map(|file| file.file_data)
+            .ok_or_else(|| Error::FileNotFound {
+                group_id: group_id.to_string(),
+                file_id: file_id.to_string(),
+            })
+    }
+
+    /// Get the user whose API token is in use, which also checks that the token is valid.
+    pub async fn get_me(&self) -> Result<User> {
+        let response = self
+            .get::<UserResponse>("/users/me", Vec::<(&str, Option<&str>)>::new())
+            .await?;
+        Ok(response.response)
+    }
+
+    /// Get every group the user is a member of. The list is cached for a day,
+    /// pass `refresh` to fetch it from the API regardless.
+    pub async fn get_all_groups(&self, refresh: bool) -> Result<Vec<Group>> {
+        /// The maximum page size allowed by the `/groups` endpoint.
+        const GROUPS_PER_PAGE: usize = 500;
+        const GROUPS_CACHE_FILENAME: &str = "groups.json";
+        const GROUPS_CACHE_MAX_AGE: TimeDelta = TimeDelta::hours(24);
+
+        if !refresh {
+            let cached = self
+                .cache
+                .read_cache_item::<CachedGroups>(GROUPS_CACHE_FILENAME)?;
+            if let Some(cached) = cached
+                && Utc::now() - cached.fetched_at < GROUPS_CACHE_MAX_AGE
+            {
+                return Ok(cached.groups);
+            }
+        }
+
+        let groups = collect_pages(GROUPS_PER_PAGE, async |page| {
             let response = self
                 .get::<GroupsResponse>(
                     "/groups",
-                    vec![("per_page", Some(10)), ("page", Some(page))],
+                    vec![("per_page", Some(GROUPS_PER_PAGE)), ("page", Some(page))],
                 )
                 .await?;
-            if response.response.is_empty() {
-                break;
-            }
+            Ok(response.response)
+        })
+        .await?;
 
-            page += 1;
-            groups.extend(response.response);
-            if groups.len() > 100 {
-                break;
-            }
-        }
+        let cached = CachedGroups {
+            fetched_at: Utc::now(),
+            groups,
+        };
+        self.cache
+            .write_cache_item(GROUPS_CACHE_FILENAME, &cached)?;
+
+        Ok(cached.groups)
+    }
 
-        Ok(groups)
+    /// Get the groups the user has left, which [`Self::get_all_groups`] doesn't include.
+    pub async fn get_former_groups(&self) -> Result<Vec<Group>> {
+        let response = self
+            .get::<GroupsResponse>("/groups/former", Vec::<(&str, Option<&str>)>::new())
+            .await?;
+        Ok(response.response)
     }
 
-    /// Stream all messages
+    /// Get every direct message conversation the user is part of.
+    pub async fn get_all_chats(&self) -> Result<Vec<Chat>> {
+        /// The maximum page size allowed by the `/chats` endpoint.
+        const CHATS_PER_PAGE: usize = 100;
+
+        collect_pages(CHATS_PER_PAGE, async |page| {
+            let response = self
+                .get::<ChatsResponse>(
+                    "/chats",
+                    vec![("per_page", Some(CHATS_PER_PAGE)), ("page", Some(page))],
+                )
+                .await?;
+            Ok(response.response)
+        })
+        .await
+    }
+
+    /// Stream the messages in a group sent between `oldest` and `newest`, newest
+    /// first, starting from the message `before_id` if given (not included).
     pub async fn get_messages(
         &self,
         newest: DateTime<Utc>,
         oldest: DateTime<Utc>,
         group_id: String,
-    ) -> miette::Result<impl Stream<Item = miette::Result<Message>>> {
+        before_id: Option<String>,
+    ) -> Result<impl Stream<Item = Result<Message>>> {
         if newest <= oldest {
-            miette::bail!(
-                "Newest date {} must be later than oldest date {}",
-                newest,
-                oldest
-            );
+            return Err(Error::InvalidDateRange { newest, oldest });
         }
 
         let client = self.clone();
-        let mut before_id: Option<String> = None;
-
-        Ok(async_stream::try_stream! {
-            loop {
-                let messages_page = client.get::<GroupMessagesResponse>(
-                    format!("/groups/{group_id}/messages"),
-                    vec![
-                        ("limit", Some("100".to_string())),
-                        ("before_id", before_id),
-                    ]
-                ).await?.response;
-
-                before_id = messages_page.next_page_before_id();
-                if before_id.is_none() {
-                    return;
-                }
+        let per_page = self.messages_per_page;
 
-                for message in messages_page.messages {
-                    if message.created_at < oldest {
-                        // we have gone outside of our filter range
-                        return;
-                    }
-                    if message.created_at > newest {
-                        // we are paginating backwards
-                        continue;
-                    }
-                    yield message;
-                }
+        Ok(stream_message_pages(
+            newest,
+            oldest,
+            before_id,
+            per_page,
+            async move |before_id| {
+                let response = client
+                    .get::<GroupMessagesResponse>(
+                        format!("/groups/{group_id}/messages"),
+                        vec![
+                            ("limit", Some(per_page.to_string())),
+                            ("before_id", before_id),
+                        ],
+                    )
+                    .await?;
+                Ok(response.response)
+            },
+        ))
+    }
 
-                tokio::time::sleep(Duration::from_secs(1)).await
-            }
-        })
+    /// Get the message `message_id` in a group. There is no endpoint for a single
+    /// message, so this asks for the first message after the id just before it.
+    pub async fn get_message(&self, group_id: &str, message_id: &str) -> Result<Message> {
+        let not_found = || Error::MessageNotFound {
+            group_id: group_id.to_string(),
+            message_id: message_id.to_string(),
+        };
+        let after_id = previous_id(message_id).ok_or_else(not_found)?;
+
+        let page = self
+            .get_unless_not_modified::<GroupMessagesResponse>(
+                format!("/groups/{group_id}/messages"),
+                vec![
+                    ("limit", Some("1".to_string())),
+                    ("after_id", Some(after_id)),
+                ],
+            )
+            .await?;
+        page.and_then(|page| page.response.messages.into_iter().next())
+            .filter(|message| message.id == message_id)
+            .ok_or_else(not_found)
+    }
+
+    /// Get the newest page of messages in a group, along with its total message count.
+    pub async fn get_latest_messages(&self, group_id: &str) -> Result<GroupMessagesPage> {
+        let response = self
+            .get::<GroupMessagesResponse>(
+                format!("/groups/{group_id}/messages"),
+                vec![("limit", Some(self.messages_per_page))],
+            )
+            .await?;
+        Ok(response.response)
+    }
+
+    /// Stream the messages in a group sent after the message `since_id`, oldest first.
+    pub async fn get_messages_since(
+        &self,
+        group_id: String,
+        since_id: String,
+    ) -> Result<impl Stream<Item = Result<Message>>> {
+        let client = self.clone();
+        let per_page = self.messages_per_page;
+
+        // `since_id` only returns the *most recent* messages after it, skipping any
+        // in between when there are more than a page of them, whereas `after_id`
+        // returns those immediately after it, in chronological order
+        Ok(stream_messages_after(
+            since_id,
+            per_page,
+            async move |after_id| {
+                let response = client
+                    .get_unless_not_modified::<GroupMessagesResponse>(
+                        format!("/groups/{group_id}/messages"),
+                        vec![
+                            ("limit", Some(per_page.to_string())),
+                            ("after_id", Some(after_id)),
+                        ],
+                    )
+                    .await?;
+                Ok(response.map(|response| response.response))
+            },
+        ))
+    }
+
+    /// Stream the messages in a group sent between the messages `after_id` and
+    /// `before_id`, neither included. Either end may be left open: without
+    /// `after_id`, messages are streamed newest first back to the group's first
+    /// message, otherwise oldest first up to the newest message.
+    pub async fn get_messages_between(
+        &self,
+        group_id: String,
+        after_id: Option<String>,
+        before_id: Option<String>,
+    ) -> Result<impl Stream<Item = Result<Message>>> {
+        if let Some(after_id) = after_id {
+            let messages = self.get_messages_since(group_id, after_id).await?;
+            return Ok(messages
+                .take_while(move |message| {
+                    let before = match (message, &before_id) {
+                        (Ok(message), Some(before_id)) => is_older(&message.id, before_id),
+                        _ => true,
+                    };
+                    std::future::ready(before)
+                })
+                .left_stream());
+        }
+
+        let client = self.clone();
+        let per_page = self.messages_per_page;
+
+        Ok(stream_message_pages(
+            DateTime::<Utc>::MAX_UTC,
+            DateTime::<Utc>::MIN_UTC,
+            before_id,
+            per_page,
+            async move |before_id| {
+                let response = client
+                    .get::<GroupMessagesResponse>(
+                        format!("/groups/{group_id}/messages"),
+                        vec![
+                            ("limit", Some(per_page.to_string())),
+                            ("before_id", before_id),
+                        ],
+                    )
+                    .await?;
+                Ok(response.response)
+            },
+        )
+        .right_stream())
+    }
+
+    /// Stream the direct messages with `other_user_id` sent between `oldest` and
+    /// `newest`, newest first, starting from the message `before_id` if given (not included).
+    pub async fn get_direct_messages(
+        &self,
+        newest: DateTime<Utc>,
+        oldest: DateTime<Utc>,
+        other_user_id: String,
+        before_id: Option<String>,
+    ) -> Result<impl Stream<Item = Result<Message>>> {
+        if newest <= oldest {
+            return Err(Error::InvalidDateRange { newest, oldest });
+        }
+
+        /// The page size returned by the `/direct_messages` endpoint.
+        const DIRECT_MESSAGES_PER_PAGE: usize = 20;
+
+        let client = self.clone();
+
+        Ok(stream_message_pages(
+            newest,
+            oldest,
+            before_id,
+            DIRECT_MESSAGES_PER_PAGE,
+            async move |before_id| {
+                let response = client
+                    .get::<DirectMessagesResponse>(
+                        "/direct_messages",
+                        vec![
+                            ("other_user_id", Some(other_user_id.clone())),
+                            ("before_id", before_id),
+                        ],
+                    )
+                    .await?;
+                Ok(response.response.into())
+            },
+        ))
     }
 
     /// make a GET request
@@ -105,29 +476,786 @@ impl Client {
         &self,
         path: impl Display,
         query: Vec<(impl Display, Option<impl Display>)>,
-    ) -> miette::Result<T>
+    ) -> Result<T>
     where
         for<'de> T: Deserialize<'de>,
     {
-        let token = &self.config.api_token;
+        let path = path.to_string();
+        self.get_unless_not_modified(&path, query)
+            .await?
+            .ok_or(Error::NotModified { path })
+    }
 
-        let query = query
-            .into_iter()
-            .filter_map(|(k, v)| v.map(|v| format!("{k}={v}")))
-            .chain(std::iter::once(format!("token={token}")))
-            .collect::<Vec<_>>()
-            .join("&");
+    /// make a GET request, returning `None` for a `304 Not Modified` response,
+    /// which is how the messages endpoints say there are no (more) messages
+    async fn get_unless_not_modified<T>(
+        &self,
+        path: impl Display,
+        query: Vec<(impl Display, Option<impl Display>)>,
+    ) -> Result<Option<T>>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        let url = api_url(
+            self.config.api_base_url(),
+            &path.to_string(),
+            query,
+            self.config.api_token.expose(),
+        )?;
 
-        let href = format!("https://api.groupme.com/v3{path}?{query}");
+        retry::with_retries(self.max_retries, || async {
+            let waiting_since = Instant::now();
+            self.limiter.acquire().await;
+            let waited = waiting_since.elapsed();
+            let started_at = Instant::now();
+            let HttpResponse {
+                status,
+                headers,
+                body,
+            } = self.transport.get(url.clone()).await?;
+            // only log the path, the full URL includes the API token
+            debug!(
+                "GET {path} {status} in {:?}, after waiting {waited:?} between requests",
+                started_at.elapsed()
+            );
 
-        let bytes = reqwest::get(href)
+            if status == StatusCode::NOT_MODIFIED {
+                return Ok(None);
+            }
+            if !status.is_success() {
+                let error = status_error(status, &body);
+                return Err(Failure::from_status(status, &headers, error));
+            }
+
+            Ok(Some(serde_path_to_error::deserialize(
+                &mut serde_json::Deserializer::from_slice(&body),
+            )?))
+        })
+        .await
+    }
+}
+
+/// The URL a File attachment of a group is downloaded from, see [`Client::download_request`].
+pub fn file_url(group_id: &str, file_id: &str) -> String {
+    format!("{FILE_API_BASE_URL}/{group_id}/files/{file_id}")
+}
+
+/// Build a GroupMe API URL, percent-encoding the query parameters and token.
+fn api_url(
+    base_url: &str,
+    path: &str,
+    query: Vec<(impl Display, Option<impl Display>)>,
+    token: &str,
+) -> Result<Url> {
+    let mut url = Url::parse(&format!("{}{path}", base_url.trim_end_matches('/')))?;
+    url.query_pairs_mut()
+        .extend_pairs(
+            query
+                .into_iter()
+                .filter_map(|(k, v)| v.map(|v| (k.to_string(), v.to_string()))),
+        )
+        .append_pair("token", token);
+    Ok(url)
+}
+
+/// Describe a non-2xx API response, including GroupMe's own error details when
+/// the body contains them.
+fn status_error(status: StatusCode, body: &[u8]) -> Error {
+    let details =
+        serde_json::from_slice::<ErrorResponse>(body)
+            .ok()
+            .map(|ErrorResponse { meta }| ApiDetails {
+                code: meta.code,
+                errors: meta.errors,
+            });
+
+    match status {
+        StatusCode::UNAUTHORIZED => Error::Unauthorized { details },
+        StatusCode::TOO_MANY_REQUESTS => Error::RateLimited { details },
+        _ => Error::Api { status, details },
+    }
+}
+
+/// Collect items from a 1-indexed paginated endpoint, requesting pages until one
+/// comes back shorter than `per_page` (or empty).
+async fn collect_pages<T>(
+    per_page: usize,
+    mut fetch_page: impl AsyncFnMut(usize) -> Result<Vec<T>>,
+) -> Result<Vec<T>> {
+    let mut items = Vec::new();
+    let mut page = 1;
+
+    loop {
+        let page_items = fetch_page(page).await?;
+        let is_last_page = page_items.len() < per_page;
+        items.extend(page_items);
+
+        if is_last_page {
+            return Ok(items);
+        }
+        page += 1;
+    }
+}
+
+/// Walk backwards through pages of messages, newest first, from the message
+/// `before_id` (or the newest message) yielding those created between `oldest`
+/// and `newest`. Pagination stops once a page comes back shorter than
+/// `per_page`, or once we have gone past `oldest`.
+fn stream_message_pages(
+    newest: DateTime<Utc>,
+    oldest: DateTime<Utc>,
+    before_id: Option<String>,
+    per_page: usize,
+    mut fetch_page: impl AsyncFnMut(Option<String>) -> Result<GroupMessagesPage>,
+) -> impl Stream<Item = Result<Message>> {
+    async_stream::try_stream! {
+        let mut before_id = before_id;
+        let started_at = Instant::now();
+
+        for number in 1.. {
+            let fetched_at = Instant::now();
+            let messages_page = fetch_page(before_id)
+                .instrument(debug_span!("page", number))
+                .await?;
+            log_page(number, &messages_page, fetched_at, started_at);
+            let is_last_page = messages_page.messages.len() < per_page;
+            before_id = messages_page.next_page_before_id();
+
+            for message in messages_page.messages {
+                if message.created_at < oldest {
+                    // we have gone outside of our filter range
+                    return;
+                }
+                if message.created_at > newest {
+                    // we are paginating backwards
+                    continue;
+                }
+                yield message;
+            }
+
+            if is_last_page || before_id.is_none() {
+                return;
+            }
+        }
+    }
+}
+
+/// Log how long the page of messages `number` took to fetch, having started fetching
+/// it at `fetched_at`, what it holds, and how long ago pagination started at `started_at`.
+fn log_page(number: usize, page: &GroupMessagesPage, fetched_at: Instant, started_at: Instant) {
+    let attachments = page
+        .messages
+        .iter()
+        .map(|message| message.attachments.len())
+        .sum::<usize>();
+    debug!(
+        "page {number}: {} messages with {attachments} attachments in {:?}, {:?} since the first page",
+        page.messages.len(),
+        fetched_at.elapsed(),
+        started_at.elapsed()
+    );
+}
+
+/// Whether the message `id` was sent before the message `other_id`. Message ids
+/// are increasing numbers, which may be too large for an integer type.
+fn is_older(id: &str, other_id: &str) -> bool {
+    (id.len(), id) < (other_id.len(), other_id)
+}
+
+/// The id one less than the message id `id`, if it is a number greater than zero.
+fn previous_id(id: &str) -> Option<String> {
+    let id = id.parse::<u128>().ok()?;
+    Some(id.checked_sub(1)?.to_string())
+}
+
+/// Stream messages forward from the message `after_id`, fetching pages of up
+/// to `per_page` messages (oldest first) with `fetch_page`, which returns `None`
+/// when there are no more.
+fn stream_messages_after(
+    after_id: String,
+    per_page: usize,
+    mut fetch_page: impl AsyncFnMut(String) -> Result<Option<GroupMessagesPage>>,
+) -> impl Stream<Item = Result<Message>> {
+    async_stream::try_stream! {
+        let mut after_id = after_id;
+        let started_at = Instant::now();
+
+        for number in 1.. {
+            let fetched_at = Instant::now();
+            let Some(messages_page) = fetch_page(after_id.clone())
+                .instrument(debug_span!("page", number))
+                .await?
+            else {
+                return;
+            };
+            log_page(number, &messages_page, fetched_at, started_at);
+            let is_last_page = messages_page.messages.len() < per_page;
+            let Some(last) = messages_page.messages.last() else {
+                return;
+            };
+            after_id = last.id.clone();
+
+            for message in messages_page.messages {
+                yield message;
+            }
+
+            if is_last_page {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use chrono::TimeZone;
+    use futures_util::{StreamExt, pin_mut};
+    use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+
+    use super::*;
+
+    /// A request's path and `before_id` query parameter, or `page` for paged lists.
+    type FakeRequest = (&'static str, Option<&'static str>);
+
+    /// An [`HttpTransport`] answering with canned responses by [`FakeRequest`].
+    #[derive(Clone)]
+    struct FakeTransport {
+        responses: Arc<Vec<(FakeRequest, (StatusCode, String))>>,
+    }
+
+    impl FakeTransport {
+        fn new(responses: impl IntoIterator<Item = (FakeRequest, (StatusCode, String))>) -> Self {
+            Self {
+                responses: Arc::new(responses.into_iter().collect()),
+            }
+        }
+    }
+
+    impl HttpTransport for FakeTransport {
+        async fn get(&self, url: Url) -> Result<HttpResponse, Failure> {
+            let before_id = url
+                .query_pairs()
+                .find(|(key, _)| key == "before_id" || key == "page")
+                .map(|(_, value)| value.to_string());
+            let (status, body) = self
+                .responses
+                .iter()
+                .find(|((path, id), _)| *path == url.path() && id.map(str::to_string) == before_id)
+                .map(|(_, response)| response.clone())
+                .unwrap_or_else(|| panic!("unexpected request {url}"));
+
+            Ok(HttpResponse {
+                status,
+                headers: HeaderMap::new(),
+                body: body.into(),
+            })
+        }
+    }
+
+    fn fake_config() -> Config {
+        Config {
+            api_token: "token".to_string().into(),
+            api_token_in_keyring: false,
+            image_dir: ".".into(),
+            filename_template: None,
+            api_base_url: None,
+            proxy: None,
+            user_agent: None,
+        }
+    }
+
+    fn fake_client(transport: FakeTransport) -> Client<FakeTransport> {
+        Client::with_transport(
+            Cache::in_dir(Path::new("unused")),
+            fake_config(),
+            transport,
+            reqwest::Client::new(),
+        )
+        .with_page_delay(Duration::ZERO)
+    }
+
+    fn messages_page_json(ids: impl Iterator<Item = usize>) -> String {
+        let messages = ids
+            .map(|id| serde_json::to_value(message(&id.to_string(), 1_000 + id as i64)).unwrap())
+            .collect::<Vec<_>>();
+        serde_json::json!({
+            "meta": {"code": 200},
+            "response": {"count": messages.len(), "messages": messages},
+        })
+        .to_string()
+    }
+
+    fn message(id: &str, created_at: i64) -> Message {
+        Message {
+            id: id.to_string(),
+            source_guid: id.to_string(),
+            created_at: Utc.timestamp_opt(created_at, 0).unwrap(),
+            user_id: "1".to_string(),
+            group_id: Some("1".to_string()),
+            recipient_id: None,
+            name: "user".to_string(),
+            avatar_url: None,
+            text: None,
+            system: false,
+            favorited_by: vec![],
+            attachments: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn collect_pages_reads_until_a_short_page() {
+        let pages = [vec![1, 2, 3], vec![4, 5, 6], vec![7]];
+        let mut requested = Vec::new();
+
+        let items = collect_pages(3, async |page| {
+            requested.push(page);
+            Ok(pages[page - 1].clone())
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(items, [1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(requested, [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn collect_pages_stops_on_an_empty_page() {
+        let pages = [vec![1, 2], vec![3, 4], vec![]];
+
+        let items = collect_pages(2, async |page| Ok(pages[page - 1].clone()))
             .await
-            .into_diagnostic()?
-            .bytes()
+            .unwrap();
+
+        assert_eq!(items, [1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn stream_message_pages_yields_the_last_short_page() {
+        let mut requested = Vec::new();
+
+        let mut ids = Vec::new();
+        {
+            let messages = stream_message_pages(
+                Utc.timestamp_opt(1_000, 0).unwrap(),
+                Utc.timestamp_opt(0, 0).unwrap(),
+                None,
+                2,
+                async |before_id: Option<String>| {
+                    requested.push(before_id.clone());
+                    let messages = match before_id.as_deref() {
+                        None => vec![message("4", 40), message("3", 30)],
+                        Some("3") => vec![message("2", 20)],
+                        Some(other) => panic!("unexpected before_id {other}"),
+                    };
+                    Ok(GroupMessagesPage { count: 3, messages })
+                },
+            );
+            pin_mut!(messages);
+
+            while let Some(message) = messages.next().await {
+                ids.push(message.unwrap().id);
+            }
+        }
+
+        assert_eq!(ids, ["4", "3", "2"]);
+        assert_eq!(requested, [None, Some("3".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn stream_messages_after_walks_forward_until_not_modified() {
+        let mut requested = Vec::new();
+
+        let mut ids = Vec::new();
+        {
+            let messages = stream_messages_after("1".to_string(), 2, async |after_id: String| {
+                requested.push(after_id.clone());
+                let messages = match after_id.as_str() {
+                    "1" => vec![message("2", 20), message("3", 30)],
+                    "3" => vec![message("4", 40), message("5", 50)],
+                    "5" => return Ok(None),
+                    other => panic!("unexpected after_id {other}"),
+                };
+                Ok(Some(GroupMessagesPage { count: 5, messages }))
+            });
+            pin_mut!(messages);
+
+            while let Some(message) = messages.next().await {
+                ids.push(message.unwrap().id);
+            }
+        }
+
+        assert_eq!(ids, ["2", "3", "4", "5"]);
+        assert_eq!(requested, ["1", "3", "5"]);
+    }
+
+    #[tokio::test]
+    async fn get_messages_between_starts_before_the_given_id() {
+        let client = fake_client(FakeTransport::new([(
+            ("/v3/groups/1/messages", Some("50")),
+            (StatusCode::OK, messages_page_json((1..=49).rev())),
+        )]));
+
+        let messages = client
+            .get_messages_between("1".to_string(), None, Some("50".to_string()))
             .await
-            .into_diagnostic()?;
+            .unwrap();
+        pin_mut!(messages);
+
+        let mut ids = Vec::new();
+        while let Some(message) = messages.next().await {
+            ids.push(message.unwrap().id);
+        }
+        assert_eq!(ids.first().map(String::as_str), Some("49"));
+        assert_eq!(ids.len(), 49);
+    }
+
+    #[test]
+    fn messages_per_page_is_capped() {
+        let client = fake_client(FakeTransport::new([]));
+        assert_eq!(
+            client.clone().with_messages_per_page(500).messages_per_page,
+            MAX_MESSAGES_PER_PAGE
+        );
+        assert_eq!(client.with_messages_per_page(20).messages_per_page, 20);
+    }
 
-        serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_slice(&bytes))
-            .into_diagnostic()
+    #[test]
+    fn is_older_compares_ids_as_numbers() {
+        assert!(is_older("9", "10"));
+        assert!(is_older("171000000000000001", "171000000000000002"));
+        assert!(!is_older("10", "9"));
+        assert!(!is_older("10", "10"));
+    }
+
+    #[test]
+    fn previous_id_counts_down() {
+        assert_eq!(
+            previous_id("171000000000000000").unwrap(),
+            "170999999999999999"
+        );
+        assert_eq!(previous_id("1").unwrap(), "0");
+        assert!(previous_id("0").is_none());
+        assert!(previous_id("abc").is_none());
+    }
+
+    #[test]
+    fn api_url_escapes_query_parameters() {
+        let url = api_url(
+            crate::config::DEFAULT_API_BASE_URL,
+            "/groups",
+            vec![("name", Some("a b&c=d"))],
+            "to+ken",
+        )
+        .unwrap();
+
+        assert_eq!(
+            url.as_str(),
+            "https://api.groupme.com/v3/groups?name=a+b%26c%3Dd&token=to%2Bken"
+        );
+    }
+
+    #[tokio::test]
+    async fn get_messages_through_a_transport() {
+        let client = fake_client(FakeTransport::new([
+            (
+                ("/v3/groups/1/messages", None),
+                (StatusCode::OK, messages_page_json((1..=100).rev())),
+            ),
+            (
+                ("/v3/groups/1/messages", Some("1")),
+                (StatusCode::OK, messages_page_json([0].into_iter())),
+            ),
+        ]));
+
+        let messages = client
+            .get_messages(
+                Utc.timestamp_opt(2_000, 0).unwrap(),
+                Utc.timestamp_opt(0, 0).unwrap(),
+                "1".to_string(),
+                None,
+            )
+            .await
+            .unwrap();
+        pin_mut!(messages);
+
+        let mut count = 0;
+        while let Some(message) = messages.next().await {
+            message.unwrap();
+            count += 1;
+        }
+        assert_eq!(count, 101);
+    }
+
+    #[tokio::test]
+    async fn unauthorized_responses_mention_the_api_token() {
+        let client = fake_client(FakeTransport::new([(
+            ("/v3/groups", Some("1")),
+            (
+                StatusCode::UNAUTHORIZED,
+                r#"{"meta": {"code": 401, "errors": ["unauthorized"]}}"#.to_string(),
+            ),
+        )]));
+
+        let err = client.get_all_groups(true).await.unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "401 Unauthorized: check your API token (GroupMe code 401: unauthorized)"
+        );
+    }
+
+    /// An [`HttpTransport`] answering each request with the next of a sequence of
+    /// responses, whatever was requested, and counting the requests.
+    #[derive(Clone)]
+    struct SequenceTransport {
+        responses: Arc<std::sync::Mutex<VecDeque<HttpResponse>>>,
+        requests: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl SequenceTransport {
+        fn new(responses: impl IntoIterator<Item = HttpResponse>) -> Self {
+            Self {
+                responses: Arc::new(std::sync::Mutex::new(responses.into_iter().collect())),
+                requests: Arc::default(),
+            }
+        }
+
+        fn requests(&self) -> usize {
+            self.requests.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    impl HttpTransport for SequenceTransport {
+        async fn get(&self, url: Url) -> Result<HttpResponse, Failure> {
+            self.requests
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let response = self.responses.lock().unwrap().pop_front();
+            Ok(response.unwrap_or_else(|| panic!("unexpected request {url}")))
+        }
+    }
+
+    /// A directory for a test's cache, removed when dropped.
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "groupme-download-test-{}-{name}",
+                std::process::id()
+            ));
+            std::fs::create_dir_all(dir.join("cache")).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn groups_page_json(ids: impl Iterator<Item = usize>) -> String {
+        let groups = ids
+            .map(|id| {
+                serde_json::json!({
+                    "id": id.to_string(),
+                    "name": format!("group {id}"),
+                    "type": "private",
+                    "description": "",
+                    "creator_user_id": "1",
+                    "image_url": null,
+                    "share_url": null,
+                    "created_at": 1_000,
+                    "updated_at": 2_000,
+                    "members": [],
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::json!({"meta": {"code": 200}, "response": groups}).to_string()
+    }
+
+    #[tokio::test]
+    async fn get_all_groups_reads_every_page() {
+        let dir = TempDir::new("groups");
+        let client = Client::with_transport(
+            Cache::in_dir(&dir.0),
+            fake_config(),
+            FakeTransport::new([
+                (
+                    ("/v3/groups", Some("1")),
+                    (StatusCode::OK, groups_page_json(1..=500)),
+                ),
+                (
+                    ("/v3/groups", Some("2")),
+                    (StatusCode::OK, groups_page_json(501..=502)),
+                ),
+            ]),
+            reqwest::Client::new(),
+        );
+
+        let groups = client.get_all_groups(true).await.unwrap();
+
+        assert_eq!(groups.len(), 502);
+        assert_eq!(groups.last().unwrap().id, "502");
+    }
+
+    #[tokio::test]
+    async fn get_former_groups_reads_the_former_groups() {
+        let client = fake_client(FakeTransport::new([(
+            ("/v3/groups/former", None),
+            (StatusCode::OK, groups_page_json(7..=8)),
+        )]));
+
+        let groups = client.get_former_groups().await.unwrap();
+
+        let ids = groups
+            .iter()
+            .map(|group| group.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["7", "8"]);
+    }
+
+    #[tokio::test]
+    async fn get_messages_keeps_to_the_date_window() {
+        // only the first page is answered, so reading past the window would panic
+        let client = fake_client(FakeTransport::new([(
+            ("/v3/groups/1/messages", None),
+            (StatusCode::OK, messages_page_json((1..=100).rev())),
+        )]));
+
+        let messages = client
+            .get_messages(
+                Utc.timestamp_opt(1_050, 0).unwrap(),
+                Utc.timestamp_opt(1_020, 0).unwrap(),
+                "1".to_string(),
+                None,
+            )
+            .await
+            .unwrap();
+        pin_mut!(messages);
+
+        let mut ids = Vec::new();
+        while let Some(message) = messages.next().await {
+            ids.push(message.unwrap().id.parse::<usize>().unwrap());
+        }
+        assert_eq!(ids, (20..=50).rev().collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn rate_limited_requests_are_retried() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("0"));
+        let transport = SequenceTransport::new([
+            HttpResponse {
+                status: StatusCode::TOO_MANY_REQUESTS,
+                headers,
+                body: r#"{"meta": {"code": 429}}"#.into(),
+            },
+            HttpResponse {
+                status: StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: r#"{"meta": {"code": 200}, "response": {"id": "1", "name": "me"}}"#.into(),
+            },
+        ]);
+        let client = Client::with_transport(
+            Cache::in_dir(Path::new("unused")),
+            fake_config(),
+            transport.clone(),
+            reqwest::Client::new(),
+        );
+
+        let me = client.get_me().await.unwrap();
+
+        assert_eq!(me.name, "me");
+        assert_eq!(transport.requests(), 2);
+    }
+
+    #[tokio::test]
+    async fn rate_limiting_gives_up_after_the_retries() {
+        let response = HttpResponse {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            headers: [(RETRY_AFTER, HeaderValue::from_static("0"))]
+                .into_iter()
+                .collect(),
+            body: r#"{"meta": {"code": 429}}"#.into(),
+        };
+        let transport = SequenceTransport::new(vec![response; 3]);
+        let client = Client::with_transport(
+            Cache::in_dir(Path::new("unused")),
+            fake_config(),
+            transport.clone(),
+            reqwest::Client::new(),
+        )
+        .with_max_retries(2);
+
+        let err = client.get_me().await.unwrap_err();
+
+        assert!(matches!(err, Error::GaveUp { retries: 2, .. }), "{err:?}");
+        assert_eq!(transport.requests(), 3);
+    }
+
+    #[tokio::test]
+    async fn errors_do_not_contain_the_api_token() {
+        let token = "s3cr3t+t0ken";
+        let config = Config {
+            api_token: token.to_string().into(),
+            api_token_in_keyring: false,
+            image_dir: ".".into(),
+            filename_template: None,
+            // nothing listens on port 1, so requests fail as to an unreachable server
+            api_base_url: Some("http://127.0.0.1:1/v3".to_string()),
+            proxy: None,
+            user_agent: None,
+        };
+        let client = Client::new(
+            Cache::in_dir(Path::new("unused")),
+            config.clone(),
+            Duration::from_secs(5),
+            None,
+        )
+        .unwrap()
+        .with_max_retries(0);
+
+        let err = client.get_me().await.unwrap_err();
+
+        assert!(matches!(&err, Error::GaveUp { .. }), "{err:?}");
+        for message in [
+            err.to_string(),
+            format!("{err:?}"),
+            format!("{:?}", miette::Report::new(err)),
+        ] {
+            assert!(!message.contains("s3cr3t"), "{message}");
+        }
+        assert!(!format!("{config:?}").contains(token));
+    }
+
+    #[tokio::test]
+    async fn get_messages_resumes_from_before_id() {
+        // only the page before the message "51" is answered
+        let client = fake_client(FakeTransport::new([(
+            ("/v3/groups/1/messages", Some("51")),
+            (StatusCode::OK, messages_page_json((1..=50).rev())),
+        )]));
+
+        let messages = client
+            .get_messages(
+                Utc.timestamp_opt(2_000, 0).unwrap(),
+                Utc.timestamp_opt(0, 0).unwrap(),
+                "1".to_string(),
+                Some("51".to_string()),
+            )
+            .await
+            .unwrap();
+        pin_mut!(messages);
+
+        let mut ids = Vec::new();
+        while let Some(message) = messages.next().await {
+            ids.push(message.unwrap().id.parse::<usize>().unwrap());
+        }
+        assert_eq!(ids, (1..=50).rev().collect::<Vec<_>>());
     }
 }
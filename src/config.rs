@@ -1,27 +1,241 @@
-use std::path::PathBuf;
+use std::{fmt, io::IsTerminal, path::PathBuf, time::Duration};
 
+use dialoguer::Password;
 use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    cache::Cache,
+    client::Client,
+    error::{Error, Result},
+    filename,
+    model::User,
+};
 
 /// User configuration which can be persisted to disk.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Config {
     /// User's GroupMe API Token.
     /// WARN: Highly secret!!
-    pub api_token: String,
+    /// Not written to the config file when [`Self::api_token_in_keyring`] is set.
+    #[serde(default, skip_serializing_if = "ApiToken::is_empty")]
+    pub api_token: ApiToken,
+
+    /// Whether the API token is stored in the OS keyring rather than the config file.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub api_token_in_keyring: bool,
 
     /// User's preferred base image download directory.
     pub image_dir: PathBuf,
+
+    /// User's preferred filename template, see [`crate::filename::FilenameFields::render`].
+    /// When unset, [`crate::filename::DEFAULT_FILENAME_TEMPLATE`] is used, and files saved
+    /// under the [`crate::filename::LEGACY_FILENAME_TEMPLATE`] before are recognized.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename_template: Option<String>,
+
+    /// Base URL of the GroupMe API, e.g. to go through a gateway or a mock server.
+    /// When unset, [`DEFAULT_API_BASE_URL`] is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_base_url: Option<String>,
+
+    /// URL of an HTTP or SOCKS5 proxy, e.g. `socks5://localhost:1080`, which requests
+    /// to both the GroupMe API and the servers attachments are downloaded from go
+    /// through. When unset, the `HTTP_PROXY`, `HTTPS_PROXY` and `ALL_PROXY`
+    /// environment variables are honored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+
+    /// The `User-Agent` requests are sent with, to both the GroupMe API and the
+    /// servers attachments are downloaded from. When unset,
+    /// [`crate::client::DEFAULT_USER_AGENT`] is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+}
+
+/// A GroupMe API token. It is masked when displayed or debugged, so that it
+/// can't end up in logs or error messages by accident.
+#[derive(Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct ApiToken(String);
+
+impl ApiToken {
+    /// The token itself, for sending it to GroupMe.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for ApiToken {
+    fn from(token: String) -> Self {
+        Self(token)
+    }
+}
+
+impl fmt::Debug for ApiToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+impl fmt::Display for ApiToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
 }
 
+/// Environment variable which, when set, overrides the stored API token.
+pub const API_TOKEN_ENV_VAR: &str = "GROUPME_API_TOKEN";
+
+/// Environment variable which, when set, overrides the stored API base URL.
+pub const API_BASE_URL_ENV_VAR: &str = "GROUPME_API_BASE";
+
+/// Environment variable which, when set, is the path of the config file to use
+/// instead of the one in the platform's config directory.
+pub const CONFIG_FILE_ENV_VAR: &str = "GROUPME_CONFIG";
+
+/// The GroupMe API's base URL.
+pub const DEFAULT_API_BASE_URL: &str = "https://api.groupme.com/v3";
+
 impl Config {
-    /// Create a new [`Config`] by supplying the `api_token`
-    /// and prompting the user for a preferred `image_dir`.
-    pub fn new(api_token: String) -> miette::Result<Self> {
-        Ok(Self {
-            api_token,
-            image_dir: rfd::FileDialog::new()
-                .pick_folder()
-                .ok_or_else(|| miette::miette!("Must pick a target folder for image downloads."))?,
-        })
+    /// Create a new [`Config`] with the `api_token` and `image_dir`, and the default
+    /// settings otherwise.
+    pub fn new(api_token: String, image_dir: PathBuf) -> Self {
+        Self {
+            api_token: api_token.into(),
+            api_token_in_keyring: false,
+            image_dir,
+            filename_template: None,
+            api_base_url: None,
+            proxy: None,
+            user_agent: None,
+        }
+    }
+
+    /// Get the effective [`Config`] of `cache`, see [`Self::resolve`], failing
+    /// with [`Error::ConfigNotFound`] if there is none.
+    pub fn load(cache: &Cache) -> Result<Self> {
+        Self::resolve(cache.read_config()?)?.ok_or(Error::ConfigNotFound)
+    }
+
+    /// Get the effective [`Config`] of `cache` like [`Self::load`], but set one up
+    /// with [`Self::prompt`] if there is none and there is a terminal to ask at.
+    /// Requests to check the API token are made as [`Client::new`] describes.
+    pub async fn load_or_prompt(
+        cache: &Cache,
+        timeout: Duration,
+        user_agent: Option<&str>,
+    ) -> Result<Self> {
+        if let Some(config) = Self::resolve(cache.read_config()?)? {
+            return Ok(config);
+        }
+        if !std::io::stdin().is_terminal() {
+            return Err(Error::ConfigNotFound);
+        }
+
+        info!("You haven't been configured yet, let's do that first.");
+        let (config, me) = Self::prompt(cache, timeout, user_agent).await?;
+        info!("Your configuration has been saved for {}.", me.name);
+        // the environment may still override the base URL
+        Ok(Self::resolve(Some(config))?.expect("a stored config resolves"))
+    }
+
+    /// Prompt the user for their API token and a preferred `image_dir`, then save
+    /// the new [`Config`] to `cache`, as the `set-config` command does. The token is
+    /// checked before the folder is asked for, so that a rejected token doesn't
+    /// replace a working one.
+    /// Returns the config along with the user whose token it is.
+    pub async fn prompt(
+        cache: &Cache,
+        timeout: Duration,
+        user_agent: Option<&str>,
+    ) -> Result<(Self, User)> {
+        let api_token = Password::new()
+            .with_prompt("Type or paste your API token here")
+            .interact()?;
+
+        // checked as it will be used, e.g. against the base URL the environment sets,
+        // but with the token just typed, and before the user is asked for a folder
+        let unchecked = Self::new(api_token, PathBuf::new());
+        let checked = Self {
+            api_token: unchecked.api_token.clone(),
+            ..Self::resolve(Some(unchecked.clone()))?.expect("a stored config resolves")
+        };
+        let me = Client::new(cache.clone(), checked, timeout, user_agent)?
+            .get_me()
+            .await
+            .map_err(|source| Error::TokenRejected {
+                source: Box::new(source),
+            })?;
+
+        let config = Self {
+            image_dir: Self::pick_image_dir()?,
+            ..unchecked
+        };
+        cache.write_config(&config)?;
+        Ok((config, me))
+    }
+
+    /// Prompt the user for a preferred `image_dir`.
+    pub fn pick_image_dir() -> Result<PathBuf> {
+        rfd::FileDialog::new()
+            .pick_folder()
+            .ok_or(Error::NoImageDir)
+    }
+
+    /// The base URL of the GroupMe API to make requests to.
+    pub fn api_base_url(&self) -> &str {
+        self.api_base_url.as_deref().unwrap_or(DEFAULT_API_BASE_URL)
+    }
+
+    /// Resolve the effective [`Config`] from the `stored` config and the environment.
+    ///
+    /// Precedence: the [`API_TOKEN_ENV_VAR`] and [`API_BASE_URL_ENV_VAR`] environment
+    /// variables beat the values stored in the config file. Without a stored config
+    /// the token's environment variable alone is enough, in which case images are
+    /// downloaded to the current directory. A filename template which would save
+    /// files outside of the image directory is rejected.
+    pub fn resolve(stored: Option<Config>) -> Result<Option<Config>> {
+        let env_var = |name| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let env_token = env_var(API_TOKEN_ENV_VAR);
+        let env_base_url = env_var(API_BASE_URL_ENV_VAR);
+
+        let config = match (stored, env_token) {
+            (Some(config), Some(api_token)) => Self {
+                api_token: api_token.into(),
+                ..config
+            },
+            (Some(config), None) => config,
+            (None, Some(api_token)) => Self {
+                api_token: api_token.into(),
+                api_token_in_keyring: false,
+                image_dir: std::env::current_dir()?,
+                filename_template: None,
+                api_base_url: None,
+                proxy: None,
+                user_agent: None,
+            },
+            (None, None) => return Ok(None),
+        };
+        if let Some(template) = &config.filename_template {
+            filename::check_template(template).map_err(|reason| {
+                Error::InvalidFilenameTemplate {
+                    template: template.clone(),
+                    reason,
+                }
+            })?;
+        }
+
+        Ok(Some(match env_base_url {
+            Some(api_base_url) => Self {
+                api_base_url: Some(api_base_url),
+                ..config
+            },
+            None => config,
+        }))
     }
 }
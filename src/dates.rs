@@ -0,0 +1,322 @@
+use std::str::FromStr;
+
+use chrono::{
+    DateTime, Datelike, Days, FixedOffset, Local, Months, NaiveDate, NaiveDateTime, NaiveTime,
+    TimeDelta, TimeZone,
+};
+
+/// A point in time given on the command line or at a prompt.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DateArg {
+    /// A bare date, meaning midnight local time on that day.
+    Date(NaiveDate),
+    /// A date and time without an offset, interpreted as local time.
+    DateTime(NaiveDateTime),
+    /// A date and time with an explicit offset, e.g. RFC 3339.
+    Instant(DateTime<FixedOffset>),
+    /// `now`: the current time.
+    Now,
+    /// `today`: midnight local time today.
+    Today,
+    /// `7d`, `2w`, `1mo`: that long before now.
+    Ago(Span),
+    /// `last-month`: the beginning of the previous month.
+    LastMonth,
+}
+
+/// A relative amount of time, as in `7d`, `2w` or `1mo`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Span {
+    Days(u32),
+    Weeks(u32),
+    Months(u32),
+}
+
+/// The accepted formats, for help and error messages.
+pub const DATE_ARG_FORMATS: &str = "YYYY-MM-DD, YYYY-MM-DDTHH:MM[:SS], RFC 3339, now, today, \
+                                    7d, 2w, 1mo or last-month";
+
+impl DateArg {
+    /// The local time this argument refers to.
+    pub fn resolve(&self) -> Option<DateTime<Local>> {
+        self.resolve_at(Local::now())
+    }
+
+    /// The local time this argument refers to, with relative forms resolved against `now`.
+    pub fn resolve_at(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        match self {
+            Self::Now => Some(now),
+            Self::Today => start_of_day(now.date_naive(), &Local),
+            Self::Ago(Span::Days(days)) => now.checked_sub_days(Days::new((*days).into())),
+            Self::Ago(Span::Weeks(weeks)) => now.checked_sub_days(Days::new(u64::from(*weeks) * 7)),
+            Self::Ago(Span::Months(months)) => now.checked_sub_months(Months::new(*months)),
+            Self::LastMonth => round_month(now, -1),
+            Self::Date(date) => start_of_day(*date, &Local),
+            Self::DateTime(datetime) => datetime.and_local_timezone(Local).earliest(),
+            Self::Instant(instant) => Some(instant.with_timezone(&Local)),
+        }
+    }
+}
+
+impl FromStr for DateArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.to_ascii_lowercase().as_str() {
+            "now" => return Ok(Self::Now),
+            "today" => return Ok(Self::Today),
+            "last-month" => return Ok(Self::LastMonth),
+            _ => {}
+        }
+        if let Some(span) = parse_span(s) {
+            return Ok(Self::Ago(span));
+        }
+        if let Ok(instant) = DateTime::parse_from_rfc3339(s) {
+            return Ok(Self::Instant(instant));
+        }
+        for format in [
+            "%Y-%m-%dT%H:%M:%S%.f",
+            "%Y-%m-%dT%H:%M",
+            "%Y-%m-%d %H:%M:%S%.f",
+            "%Y-%m-%d %H:%M",
+        ] {
+            if let Ok(datetime) = NaiveDateTime::parse_from_str(s, format) {
+                return Ok(Self::DateTime(datetime));
+            }
+        }
+        NaiveDate::from_str(s)
+            .map(Self::Date)
+            .map_err(|_| format!("invalid date `{s}`, expected {DATE_ARG_FORMATS}"))
+    }
+}
+
+/// Parse a relative span such as `7d`, `2w` or `1mo`.
+fn parse_span(s: &str) -> Option<Span> {
+    let split = s.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = s.split_at(split);
+    let amount = amount.parse().ok()?;
+    match unit {
+        "d" => Some(Span::Days(amount)),
+        "w" => Some(Span::Weeks(amount)),
+        "mo" => Some(Span::Months(amount)),
+        _ => None,
+    }
+}
+
+/// Given a date, round to the beginning of the month, offset by `months` amount
+/// of months into the future (or the past, if negative). `None` if that is out of range.
+pub fn round_month<Tz: TimeZone>(time: DateTime<Tz>, months: i32) -> Option<DateTime<Tz>> {
+    // work on the date alone, as the same time of day may not exist in the other month
+    let date = time.date_naive().with_day(1)?;
+    let date = if months < 0 {
+        date.checked_sub_months(Months::new(months.unsigned_abs()))
+    } else {
+        date.checked_add_months(Months::new(months as u32))
+    }?;
+
+    start_of_day(date, &time.timezone())
+}
+
+/// The beginning of `date` in `tz`: midnight, unless a daylight saving change
+/// skips midnight, in which case the first time which exists on that day.
+pub fn start_of_day<Tz: TimeZone>(date: NaiveDate, tz: &Tz) -> Option<DateTime<Tz>> {
+    let mut time = date.and_time(NaiveTime::default());
+    while time.date() == date {
+        if let Some(start) = tz.from_local_datetime(&time).earliest() {
+            return Some(start);
+        }
+        // clocks move forward by a whole number of quarter hours
+        time += TimeDelta::minutes(15);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::LocalResult;
+
+    use super::*;
+
+    /// A time zone which moves its clocks forward an hour at midnight on
+    /// 2024-11-03, from UTC-3 to UTC-2, so that midnight doesn't exist that day.
+    #[derive(Clone, Copy, Debug)]
+    struct MidnightDst;
+
+    impl MidnightDst {
+        const BEFORE: i32 = -3 * 3600;
+        const AFTER: i32 = -2 * 3600;
+
+        fn change() -> NaiveDateTime {
+            NaiveDate::from_ymd_opt(2024, 11, 3)
+                .unwrap()
+                .and_time(NaiveTime::default())
+        }
+
+        fn offset(secs: i32) -> FixedOffset {
+            FixedOffset::east_opt(secs).unwrap()
+        }
+    }
+
+    impl TimeZone for MidnightDst {
+        type Offset = FixedOffset;
+
+        fn from_offset(_: &FixedOffset) -> Self {
+            MidnightDst
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_time(NaiveTime::default()))
+        }
+
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+            if *local < Self::change() {
+                LocalResult::Single(Self::offset(Self::BEFORE))
+            } else if *local < Self::change() + TimeDelta::hours(1) {
+                LocalResult::None
+            } else {
+                LocalResult::Single(Self::offset(Self::AFTER))
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_time(NaiveTime::default()))
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            if *utc < Self::change() - TimeDelta::seconds(Self::BEFORE.into()) {
+                Self::offset(Self::BEFORE)
+            } else {
+                Self::offset(Self::AFTER)
+            }
+        }
+    }
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32) -> DateTime<FixedOffset> {
+        FixedOffset::east_opt(0)
+            .unwrap()
+            .with_ymd_and_hms(year, month, day, hour, 0, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn round_month_rolls_over_year_boundaries() {
+        assert_eq!(
+            round_month(utc(2024, 12, 15, 12), 1),
+            Some(utc(2025, 1, 1, 0))
+        );
+        assert_eq!(
+            round_month(utc(2024, 1, 15, 12), -1),
+            Some(utc(2023, 12, 1, 0))
+        );
+        assert_eq!(
+            round_month(utc(2024, 3, 31, 12), 0),
+            Some(utc(2024, 3, 1, 0))
+        );
+    }
+
+    #[test]
+    fn round_month_handles_large_offsets() {
+        assert_eq!(
+            round_month(utc(2024, 3, 15, 12), -200),
+            Some(utc(2007, 7, 1, 0))
+        );
+        assert_eq!(
+            round_month(utc(2024, 3, 15, 12), 200),
+            Some(utc(2040, 11, 1, 0))
+        );
+        assert_eq!(round_month(utc(2024, 3, 15, 12), i32::MAX), None);
+        assert_eq!(round_month(utc(2024, 3, 15, 12), i32::MIN), None);
+    }
+
+    #[test]
+    fn round_month_keeps_days_which_dont_exist_in_the_other_month() {
+        // there is no February 31st, but there is a February 1st
+        assert_eq!(
+            round_month(utc(2024, 3, 31, 12), -1),
+            Some(utc(2024, 2, 1, 0))
+        );
+    }
+
+    #[test]
+    fn start_of_day_skips_a_missing_midnight() {
+        let date = NaiveDate::from_ymd_opt(2024, 11, 3).unwrap();
+        let start = start_of_day(date, &MidnightDst).unwrap();
+        assert_eq!(start.naive_local(), date.and_hms_opt(1, 0, 0).unwrap());
+
+        let before = MidnightDst
+            .with_ymd_and_hms(2024, 10, 20, 12, 0, 0)
+            .unwrap();
+        assert_eq!(
+            round_month(before, 1).unwrap().naive_local(),
+            date.with_day(1).unwrap().and_time(NaiveTime::default())
+        );
+    }
+
+    #[test]
+    fn parses_each_form() {
+        assert_eq!(
+            "2024-03-01".parse(),
+            Ok(DateArg::Date(NaiveDate::from_ymd_opt(2024, 3, 1).unwrap()))
+        );
+
+        let datetime = NaiveDate::from_ymd_opt(2024, 3, 1)
+            .unwrap()
+            .and_hms_opt(14, 30, 0)
+            .unwrap();
+        assert_eq!(
+            "2024-03-01T14:30:00".parse(),
+            Ok(DateArg::DateTime(datetime))
+        );
+        assert_eq!("2024-03-01T14:30".parse(), Ok(DateArg::DateTime(datetime)));
+        assert_eq!("2024-03-01 14:30".parse(), Ok(DateArg::DateTime(datetime)));
+
+        let offset = FixedOffset::east_opt(2 * 3600).unwrap();
+        assert_eq!(
+            "2024-03-01T14:30:00+02:00".parse(),
+            Ok(DateArg::Instant(
+                offset.from_local_datetime(&datetime).unwrap()
+            ))
+        );
+
+        assert!("March 1st".parse::<DateArg>().is_err());
+    }
+
+    #[test]
+    fn explicit_offset_is_honored() {
+        let arg: DateArg = "2024-03-01T14:30:00Z".parse().unwrap();
+        let expected = chrono::Utc.with_ymd_and_hms(2024, 3, 1, 14, 30, 0).unwrap();
+        assert_eq!(arg.resolve().unwrap().to_utc(), expected);
+    }
+
+    #[test]
+    fn relative_forms_resolve_against_now() {
+        let now = Local.with_ymd_and_hms(2024, 3, 15, 12, 0, 0).unwrap();
+        let resolve = |s: &str| s.parse::<DateArg>().unwrap().resolve_at(now).unwrap();
+
+        assert_eq!(resolve("now"), now);
+        assert_eq!(
+            resolve("today"),
+            Local.with_ymd_and_hms(2024, 3, 15, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            resolve("7d"),
+            Local.with_ymd_and_hms(2024, 3, 8, 12, 0, 0).unwrap()
+        );
+        assert_eq!(
+            resolve("2w"),
+            Local.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap()
+        );
+        assert_eq!(
+            resolve("1mo"),
+            Local.with_ymd_and_hms(2024, 2, 15, 12, 0, 0).unwrap()
+        );
+        assert_eq!(
+            resolve("last-month"),
+            Local.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap()
+        );
+
+        assert!("7y".parse::<DateArg>().is_err());
+        assert!("d".parse::<DateArg>().is_err());
+    }
+}
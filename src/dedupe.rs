@@ -0,0 +1,134 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use miette::IntoDiagnostic;
+
+use sha2::{Digest, Sha256};
+
+use crate::{cache::Cache, error::Result};
+
+/// The cache file the hashes of downloaded files are kept in.
+const HASHES_CACHE_FILENAME: &str = "hashes.json";
+
+/// Whether `filename` is the cache file holding the [`Hashes`].
+pub fn is_hashes_file(filename: &str) -> bool {
+    filename == HASHES_CACHE_FILENAME
+}
+
+/// The content hashes of downloaded files, for skipping files whose content
+/// was already downloaded under another name.
+#[derive(Default)]
+pub struct Hashes(Mutex<HashMap<String, PathBuf>>);
+
+impl Hashes {
+    /// Read the hashes of previous downloads from the cache.
+    pub fn load(cache: &Cache) -> Result<Self> {
+        let hashes = cache
+            .read_cache_item(HASHES_CACHE_FILENAME)?
+            .unwrap_or_default();
+        Ok(Self(Mutex::new(hashes)))
+    }
+
+    /// Write the hashes to the cache, for the next download.
+    pub fn save(&self, cache: &Cache) -> Result<()> {
+        cache.write_cache_item(
+            HASHES_CACHE_FILENAME,
+            &*self.0.lock().expect("not poisoned"),
+        )
+    }
+
+    /// Record that `filepath` has the content with `hash`, unless another file
+    /// which still exists already has it, in which case that file is returned.
+    pub fn claim(&self, hash: String, filepath: &Path) -> Option<PathBuf> {
+        let mut hashes = self.0.lock().expect("not poisoned");
+        if let Some(existing) = hashes.get(&hash)
+            && existing != filepath
+            && existing.is_file()
+        {
+            return Some(existing.clone());
+        }
+        hashes.insert(hash, filepath.to_path_buf());
+        None
+    }
+
+    /// Hash the files in `dir` and its subdirectories which aren't known yet, so
+    /// that content already there is recognized whatever it is named, e.g. after
+    /// files were renamed or moved. Hidden files, such as partial downloads, are
+    /// left out. Returns how many files were hashed.
+    pub fn index_dir(&self, dir: &Path) -> miette::Result<usize> {
+        let known = self
+            .0
+            .lock()
+            .expect("not poisoned")
+            .values()
+            .cloned()
+            .collect::<HashSet<_>>();
+
+        let mut dirs = vec![dir.to_path_buf()];
+        let mut hashed = 0;
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir).into_diagnostic()? {
+                let entry = entry.into_diagnostic()?;
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                let file_type = entry.file_type().into_diagnostic()?;
+                let path = entry.path();
+                if file_type.is_dir() {
+                    dirs.push(path);
+                } else if file_type.is_file() && !known.contains(&path) {
+                    let mut hasher = Sha256::new();
+                    io::copy(&mut File::open(&path).into_diagnostic()?, &mut hasher)
+                        .into_diagnostic()?;
+                    // a file already known under another name keeps that name
+                    self.0
+                        .lock()
+                        .expect("not poisoned")
+                        .entry(hex_digest(hasher))
+                        .or_insert(path);
+                    hashed += 1;
+                }
+            }
+        }
+        Ok(hashed)
+    }
+}
+
+/// Format a finished SHA-256 `hasher` as a hex string.
+pub fn hex_digest(hasher: Sha256) -> String {
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_dir_recognizes_renamed_files() {
+        let dir = std::env::temp_dir().join(format!("dedupe-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("group")).unwrap();
+        fs::write(dir.join("group/renamed.jpg"), b"jpeg").unwrap();
+        fs::write(dir.join("group/.partial.jpg.part"), b"jp").unwrap();
+
+        let hashes = Hashes::default();
+        assert_eq!(hashes.index_dir(&dir).unwrap(), 1);
+
+        let mut hasher = Sha256::new();
+        hasher.update(b"jpeg");
+        assert_eq!(
+            hashes.claim(hex_digest(hasher), &dir.join("group/new.jpg")),
+            Some(dir.join("group/renamed.jpg"))
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
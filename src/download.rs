@@ -0,0 +1,1441 @@
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use bytes::Bytes;
+use chrono::{DateTime, Local, Utc};
+use futures_util::stream::StreamExt;
+use indicatif::{MultiProgress, ProgressBar};
+use miette::{Context, IntoDiagnostic};
+use regex::Regex;
+use reqwest::StatusCode;
+use reqwest::header::CONTENT_TYPE;
+use sha2::{Digest, Sha256};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    archive::Archive,
+    cache::Cache,
+    client::{self, Client},
+    config::Config,
+    dedupe::{self, Hashes},
+    error::Error,
+    export::Exporter,
+    failures::FailedDownload,
+    filename::{
+        self, DEFAULT_FILENAME_TEMPLATE, DownloadTarget, FilenameFields, FilenameTz,
+        LEGACY_FILENAME_TEMPLATE, sanitize_filename,
+    },
+    manifest::{self, Status},
+    model::{self, AttachmentKind, Chat, Group, ImageSize, MessageAttachment},
+    progress::{self, Progress, Totals},
+    resume_state::{self, ResumeState},
+    retry::{self, Failure},
+    sidecar::{self, Sidecar},
+    sync_state::{self, SyncState},
+};
+
+/// Where messages are downloaded from.
+pub enum Conversation {
+    Group(Group),
+    Chat(Chat),
+}
+
+impl Conversation {
+    /// The name of the group, or of the other user in a direct message conversation.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Group(group) => &group.name,
+            Self::Chat(chat) => &chat.other_user.name,
+        }
+    }
+
+    /// The name of the subdirectory to download this conversation's files to.
+    pub fn dirname(&self) -> String {
+        match self {
+            Self::Group(group) => format!("{}_{}", sanitize_filename(&group.name), group.id),
+            Self::Chat(chat) => format!(
+                "dm_{}_{}",
+                sanitize_filename(&chat.other_user.name),
+                chat.other_user.id
+            ),
+        }
+    }
+
+    /// When the group was created, or the direct message conversation started.
+    pub fn created_at(&self) -> DateTime<Utc> {
+        match self {
+            Self::Group(group) => group.created_at,
+            Self::Chat(chat) => chat.created_at,
+        }
+    }
+
+    /// The key this conversation's [`SyncState`] is remembered under.
+    pub fn sync_key(&self) -> String {
+        match self {
+            Self::Group(group) => group.id.clone(),
+            Self::Chat(chat) => format!("dm_{}", chat.other_user.id),
+        }
+    }
+}
+
+/// The options of a download run which apply to every conversation in it,
+/// as given on the command line.
+#[derive(Clone, Debug)]
+pub struct DownloadOptions {
+    /// how many attachments to download at the same time
+    pub concurrency: u32,
+    pub dry_run: bool,
+    pub overwrite: bool,
+    pub flat: bool,
+    pub limit: Option<u32>,
+    pub continue_on_error: bool,
+    pub image_size: ImageSize,
+    pub tz: FilenameTz,
+    /// prepended to the name of every attachment's file, sanitized
+    pub prefix: String,
+    pub video_previews: bool,
+    pub locations: bool,
+    pub avatars: bool,
+    /// write a [`Sidecar`] next to each attachment downloaded from a message
+    pub sidecars: bool,
+    /// name files by the format of their content when their URL or content type disagrees
+    pub detect_format: bool,
+    /// skip attachments larger than this many bytes
+    pub max_size: Option<u64>,
+    /// stop downloading once this many bytes have been written in the run
+    pub max_total: Option<u64>,
+    /// report attachments as [`Event`](crate::events::Event)s on stdout, leaving it free of anything else
+    pub json: bool,
+    /// the ids of the messages to download messages after and before, instead of dates
+    pub id_range: Option<(Option<String>, Option<String>)>,
+    pub from_user: Vec<String>,
+    /// only download attachments of messages whose text contains one of these, in lowercase
+    pub text_contains: Vec<String>,
+    /// only download attachments of messages whose text matches this
+    pub regex: Option<Regex>,
+    /// skip system messages, such as members being added
+    pub skip_system: bool,
+    /// skip messages liked by fewer than this many members
+    pub min_likes: Option<u32>,
+    pub types: Vec<AttachmentKind>,
+}
+
+impl Default for DownloadOptions {
+    /// Download images and videos, four at a time, with sidecars, as the `download` command does by default.
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            dry_run: false,
+            overwrite: false,
+            flat: false,
+            limit: None,
+            continue_on_error: false,
+            image_size: ImageSize::default(),
+            tz: FilenameTz::default(),
+            prefix: String::new(),
+            video_previews: false,
+            locations: false,
+            avatars: false,
+            sidecars: true,
+            detect_format: false,
+            max_size: None,
+            max_total: None,
+            json: false,
+            id_range: None,
+            from_user: Vec::new(),
+            text_contains: Vec::new(),
+            regex: None,
+            skip_system: false,
+            min_likes: None,
+            types: vec![AttachmentKind::Image, AttachmentKind::Video],
+        }
+    }
+}
+
+/// What a [`download_group`] did.
+#[derive(Clone, Debug)]
+pub struct DownloadReport {
+    pub totals: Totals,
+    /// what happened to each attachment, as written to the group's manifest
+    pub entries: Vec<manifest::Entry>,
+}
+
+/// Download the attachments of `group` sent within `date_range` into the download
+/// directory of `config`, without showing progress.
+pub async fn download_group(
+    client: &Client,
+    config: &Config,
+    group: Group,
+    date_range: (DateTime<Local>, DateTime<Local>),
+    options: DownloadOptions,
+) -> miette::Result<DownloadReport> {
+    let run = DownloadRun::new(
+        client.clone(),
+        config,
+        options,
+        Progress::new(&progress::multi_progress(true), false),
+    );
+    let entries = run
+        .download_conversation(Conversation::Group(group), None, None, Some(date_range))
+        .await?;
+    Ok(DownloadReport {
+        totals: run.progress.totals(),
+        entries,
+    })
+}
+
+/// How many messages are scanned between saves of how far a download got, see [`ResumeState`].
+const RESUME_INTERVAL: usize = client::MAX_MESSAGES_PER_PAGE;
+
+/// What is shared by the conversations downloaded from in a run.
+pub struct DownloadRun {
+    options: DownloadOptions,
+    client: Client,
+    cache: Cache,
+    image_dir: PathBuf,
+    filename_template: String,
+    /// the template attachments may have been saved with before, see [`DownloadTarget::legacy_template`]
+    legacy_template: Option<String>,
+    pub progress: Progress,
+    /// limits how many attachments are downloaded at the same time
+    semaphore: Arc<Semaphore>,
+    pub hashes: Option<Arc<Hashes>>,
+    pub exporter: RefCell<Option<Exporter>>,
+    archive: RefCell<Option<Archive>>,
+    /// how many of the downloaded files were groups' images, which don't count toward the `--limit`
+    group_images: Cell<usize>,
+    /// the downloads which failed, kept so that they can be retried with `retry-failures`
+    pub failures: Arc<Mutex<Vec<FailedDownload>>>,
+}
+
+impl DownloadRun {
+    /// Start a run downloading into the download directory of `config`, reporting to `progress`.
+    pub fn new(
+        client: Client,
+        config: &Config,
+        options: DownloadOptions,
+        progress: Progress,
+    ) -> Self {
+        let filename_template = filename::prefix_template(
+            config
+                .filename_template
+                .as_deref()
+                .unwrap_or(DEFAULT_FILENAME_TEMPLATE),
+            &options.prefix,
+        );
+        // only the default template has changed, and prefixes came after it did
+        let legacy_template = (config.filename_template.is_none() && options.prefix.is_empty())
+            .then(|| LEGACY_FILENAME_TEMPLATE.to_string());
+        Self {
+            semaphore: Arc::new(Semaphore::new(options.concurrency as usize)),
+            options,
+            cache: client.cache().clone(),
+            client,
+            image_dir: config.image_dir.clone(),
+            filename_template,
+            legacy_template,
+            progress,
+            hashes: None,
+            exporter: RefCell::new(None),
+            archive: RefCell::new(None),
+            group_images: Cell::new(0),
+            failures: Arc::default(),
+        }
+    }
+
+    /// Don't keep files whose content was already downloaded, as recorded in `hashes`.
+    pub fn with_hashes(self, hashes: Option<Arc<Hashes>>) -> Self {
+        Self { hashes, ..self }
+    }
+
+    /// Also write the messages downloaded from to `exporter`.
+    pub fn with_exporter(self, exporter: Option<Exporter>) -> Self {
+        Self {
+            exporter: RefCell::new(exporter),
+            ..self
+        }
+    }
+
+    /// Also record the messages downloaded from, and where their attachments were saved, in `archive`.
+    pub fn with_archive(self, archive: Option<Archive>) -> Self {
+        Self {
+            archive: RefCell::new(archive),
+            ..self
+        }
+    }
+
+    /// Download the attachments of `conversation`: those sent between the messages
+    /// of the `--after-id`/`--before-id` range if given, those sent since `last_sync`
+    /// if given, those left to download from an interrupted download's date range
+    /// if `resume` is given, otherwise those sent within `date_range`. Returns what
+    /// happened to each attachment, as written to the conversation's manifest.
+    ///
+    /// While walking back through a date range, how far the download got is saved
+    /// every so often, so that it can be resumed if it is interrupted.
+    pub async fn download_conversation(
+        &self,
+        conversation: Conversation,
+        last_sync: Option<SyncState>,
+        resume: Option<ResumeState>,
+        date_range: Option<(DateTime<Local>, DateTime<Local>)>,
+    ) -> miette::Result<Vec<manifest::Entry>> {
+        let DownloadOptions {
+            dry_run,
+            overwrite,
+            flat,
+            limit,
+            continue_on_error,
+            image_size,
+            tz,
+            video_previews,
+            locations,
+            avatars,
+            sidecars,
+            ref id_range,
+            ref from_user,
+            ref text_contains,
+            ref regex,
+            skip_system,
+            min_likes,
+            ref types,
+            ..
+        } = self.options;
+        let image_dir = &self.image_dir;
+        let filename_template = &self.filename_template;
+        let client = &self.client;
+        let progress = &self.progress;
+
+        let group_users = match &conversation {
+            Conversation::Group(group) => group
+                .members
+                .iter()
+                .map(|user| (&user.user_id, user))
+                .collect::<HashMap<_, _>>(),
+            Conversation::Chat(_) => HashMap::new(),
+        };
+
+        let mut messages = match (&conversation, &last_sync, date_range) {
+            (Conversation::Group(group), _, _) if let Some((after_id, before_id)) = id_range => {
+                client
+                    .get_messages_between(group.id.to_string(), after_id.clone(), before_id.clone())
+                    .await?
+                    .boxed_local()
+            }
+            // walk forward from where the last download left off, rather than
+            // back from now until we reach it
+            (Conversation::Group(group), Some(last_sync), _) => client
+                .get_messages_since(group.id.to_string(), last_sync.message_id.clone())
+                .await?
+                .boxed_local(),
+            (Conversation::Chat(chat), Some(last_sync), _) => client
+                .get_direct_messages(
+                    Utc::now(),
+                    last_sync.created_at,
+                    chat.other_user.id.to_string(),
+                    None,
+                )
+                .await?
+                .boxed_local(),
+            (Conversation::Group(group), None, _) if let Some(resume) = &resume => client
+                .get_messages(
+                    resume.newest,
+                    resume.oldest,
+                    group.id.to_string(),
+                    Some(resume.before_id.clone()),
+                )
+                .await?
+                .boxed_local(),
+            (Conversation::Chat(chat), None, _) if let Some(resume) = &resume => client
+                .get_direct_messages(
+                    resume.newest,
+                    resume.oldest,
+                    chat.other_user.id.to_string(),
+                    Some(resume.before_id.clone()),
+                )
+                .await?
+                .boxed_local(),
+            (Conversation::Group(group), None, Some((start_date, end_date))) => client
+                .get_messages(
+                    end_date.to_utc(),
+                    start_date.to_utc(),
+                    group.id.to_string(),
+                    None,
+                )
+                .await?
+                .boxed_local(),
+            (Conversation::Chat(chat), None, Some((start_date, end_date))) => client
+                .get_direct_messages(
+                    end_date.to_utc(),
+                    start_date.to_utc(),
+                    chat.other_user.id.to_string(),
+                    None,
+                )
+                .await?
+                .boxed_local(),
+            (_, None, None) => unreachable!("a date range is chosen when there is no last sync"),
+        };
+
+        let output_dir = if flat {
+            image_dir.clone()
+        } else {
+            image_dir.join(conversation.dirname())
+        };
+        if !dry_run {
+            fs::create_dir_all(&output_dir).into_diagnostic()?;
+        }
+
+        // the date range being walked back through, if the download can be resumed
+        let resume_range = match (&resume, date_range) {
+            _ if dry_run || id_range.is_some() || last_sync.is_some() => None,
+            (Some(resume), _) => Some((resume.newest, resume.oldest)),
+            (None, Some((start_date, end_date))) => Some((end_date.to_utc(), start_date.to_utc())),
+            (None, None) => None,
+        };
+        let mut scanned = 0;
+        let mut previous_id: Option<String> = None;
+
+        let mut downloads = JoinSet::new();
+        let mut manifest = Vec::new();
+
+        let mut stream_error = None;
+        let mut newest = last_sync;
+        let mut stopped_early = false;
+
+        if let Conversation::Group(group) = &conversation {
+            stopped_early = !self
+                .queue_group_image(group, &output_dir, &mut downloads, &mut manifest)
+                .await?;
+        }
+        if avatars && !stopped_early {
+            stopped_early = !self
+                .queue_avatars(&conversation, &output_dir, &mut downloads, &mut manifest)
+                .await?;
+        }
+
+        'messages: while !stopped_early && let Some(message) = messages.next().await {
+            let message = match message {
+                Ok(message) => message,
+                Err(err) => {
+                    stream_error = Some(err);
+                    break;
+                }
+            };
+            progress.message_scanned();
+            if let Some((newest, oldest)) = resume_range
+                && scanned % RESUME_INTERVAL == 0
+                && let Some(before_id) = previous_id.take()
+            {
+                // the newer messages' attachments must all be downloaded before
+                // the download can resume from after them
+                while let Some(entry) = downloads.join_next().await {
+                    record_entry(&mut manifest, progress, entry.into_diagnostic()?);
+                }
+                // past the end of the budget, attachments are left for another run
+                if !self.budget_spent() {
+                    let state = ResumeState {
+                        before_id,
+                        newest,
+                        oldest,
+                    };
+                    resume_state::write(&self.cache, &conversation.sync_key(), &state)?;
+                }
+            }
+            scanned += 1;
+            previous_id = Some(message.id.clone());
+            if newest
+                .as_ref()
+                .is_none_or(|newest| message.created_at > newest.created_at)
+            {
+                newest = Some(SyncState {
+                    message_id: message.id.clone(),
+                    created_at: message.created_at,
+                });
+            }
+            let nickname = match &conversation {
+                Conversation::Group(_) => group_users
+                    .get(&message.user_id)
+                    .map(|user| user.nickname.as_ref())
+                    .unwrap_or_else(|| "unknown"),
+                // direct messages carry their sender's name
+                Conversation::Chat(_) => &message.name,
+            };
+
+            if skip_system && message.system {
+                continue;
+            }
+
+            if min_likes.is_some_and(|min_likes| message.favorited_by.len() < min_likes as usize) {
+                continue;
+            }
+
+            if !from_user.is_empty()
+                && !from_user
+                    .iter()
+                    .any(|user| *user == message.user_id || user.eq_ignore_ascii_case(nickname))
+            {
+                continue;
+            }
+
+            let text = message.text.as_deref().unwrap_or_default();
+            if !text_contains.is_empty() {
+                let text = text.to_lowercase();
+                if !text_contains.iter().any(|query| text.contains(query)) {
+                    continue;
+                }
+            }
+            if regex.as_ref().is_some_and(|regex| !regex.is_match(text)) {
+                continue;
+            }
+
+            if let Some(exporter) = self.exporter.borrow_mut().as_mut() {
+                exporter.write(&message, nickname)?;
+            }
+            if let Some(archive) = self.archive.borrow_mut().as_mut() {
+                archive.write_message(&message, &conversation.sync_key(), nickname)?;
+            }
+
+            let user_name = sanitize_filename(nickname);
+            let sidecar = sidecars.then(|| Sidecar::new(&message, conversation.name(), nickname));
+
+            let date = tz.convert(message.created_at);
+
+            for (index, attachment) in message.attachments.iter().enumerate() {
+                while let Some(entry) = downloads.try_join_next() {
+                    record_entry(&mut manifest, progress, entry.into_diagnostic()?);
+                }
+                if !continue_on_error && progress.failed() > 0 {
+                    stopped_early = true;
+                    break 'messages;
+                }
+                if limit.is_some_and(|limit| self.limit_count() >= limit as usize) {
+                    stopped_early = true;
+                    break 'messages;
+                }
+
+                if let MessageAttachment::Location { lat, lon, name } = attachment {
+                    if !locations {
+                        continue;
+                    }
+                    let target = DownloadTarget {
+                        dir: output_dir.clone(),
+                        template: filename_template.clone(),
+                        variant: None,
+                        fields: FilenameFields {
+                            created_at: date,
+                            user: user_name.clone(),
+                            index,
+                            message_id: message.id.clone(),
+                        },
+                        legacy_template: None,
+                    };
+                    if dry_run {
+                        self.report(format!(
+                            "would write location: {:?}",
+                            target.filepath(LOCATION_EXT)
+                        ));
+                        continue;
+                    }
+                    let entry = manifest::Entry {
+                        group: conversation.name().to_string(),
+                        message_id: message.id.clone(),
+                        created_at: Some(message.created_at),
+                        user: nickname.to_string(),
+                        url: format!("geo:{lat},{lon}"),
+                        path: None,
+                        status: Status::Skipped,
+                        size: None,
+                        mislabeled_ext: None,
+                        error: None,
+                    };
+                    let location = Location {
+                        lat,
+                        lon,
+                        name,
+                        user: nickname,
+                    };
+                    let entry = match write_location(&target, &location, overwrite) {
+                        Ok((path, status)) => manifest::Entry {
+                            path: Some(path),
+                            status,
+                            ..entry
+                        },
+                        Err(err) => manifest::Entry {
+                            status: Status::Failed,
+                            error: Some(format!("{err:?}")),
+                            ..entry
+                        },
+                    };
+                    record_entry(&mut manifest, progress, entry);
+                    continue;
+                }
+
+                if !attachment.kind().is_some_and(|kind| types.contains(&kind)) {
+                    continue;
+                }
+
+                let files = if let MessageAttachment::File { file_id } = attachment {
+                    let Conversation::Group(group) = &conversation else {
+                        debug!("skipping file attachment in direct messages: #{file_id}");
+                        continue;
+                    };
+                    let url = client::file_url(&group.id, file_id);
+                    // files keep their original name, which takes an extra request to look up
+                    let file_data = match client.get_file_data(&group.id, file_id).await {
+                        Ok(file_data) => file_data,
+                        Err(err) => {
+                            record_entry(
+                                &mut manifest,
+                                progress,
+                                manifest::Entry {
+                                    group: conversation.name().to_string(),
+                                    message_id: message.id.clone(),
+                                    created_at: Some(message.created_at),
+                                    user: nickname.to_string(),
+                                    url,
+                                    path: None,
+                                    status: Status::Failed,
+                                    size: None,
+                                    mislabeled_ext: None,
+                                    error: Some(format!("{err:?}")),
+                                },
+                            );
+                            continue;
+                        }
+                    };
+                    let file_name = Path::new(&file_data.file_name);
+                    let stem = file_name.file_stem().unwrap_or_default().to_string_lossy();
+                    let ext = file_name
+                        .extension()
+                        .map(|ext| Cow::Owned(ext.to_string_lossy().to_ascii_lowercase()));
+                    vec![(
+                        Cow::Owned(url),
+                        ext,
+                        Some(Cow::Owned(sanitize_filename(&stem))),
+                    )]
+                } else {
+                    let Some((url, provisional_ext)) = attachment.get_download_url_and_ext() else {
+                        continue;
+                    };
+                    let mut files = vec![(
+                        match attachment {
+                            MessageAttachment::Image { .. }
+                            | MessageAttachment::LinkedImage { .. } => image_size.apply(url),
+                            _ => Cow::Borrowed(url),
+                        },
+                        provisional_ext.map(Cow::Borrowed),
+                        None,
+                    )];
+                    if video_previews
+                        && let MessageAttachment::Video { preview_url, .. } = attachment
+                    {
+                        files.push((
+                            Cow::Borrowed(preview_url.as_str()),
+                            Some(Cow::Borrowed("jpg")),
+                            Some(Cow::Borrowed("preview")),
+                        ));
+                    }
+                    files
+                };
+
+                for (url, provisional_ext, variant) in files {
+                    // the extension is only final once we have the response's content type
+                    let target = DownloadTarget {
+                        dir: output_dir.clone(),
+                        template: filename_template.clone(),
+                        variant,
+                        fields: FilenameFields {
+                            created_at: date,
+                            user: user_name.clone(),
+                            index,
+                            message_id: message.id.clone(),
+                        },
+                        legacy_template: self.legacy_template.clone(),
+                    };
+
+                    let entry = manifest::Entry {
+                        group: conversation.name().to_string(),
+                        message_id: message.id.clone(),
+                        created_at: Some(message.created_at),
+                        user: nickname.to_string(),
+                        url: url.to_string(),
+                        path: None,
+                        status: Status::Skipped,
+                        size: None,
+                        mislabeled_ext: None,
+                        error: None,
+                    };
+
+                    if !self
+                        .queue_download(
+                            &mut downloads,
+                            &mut manifest,
+                            &url,
+                            provisional_ext,
+                            target,
+                            entry,
+                            sidecar.clone(),
+                        )
+                        .await?
+                    {
+                        stopped_early = true;
+                        break 'messages;
+                    }
+                }
+            }
+        }
+
+        while let Some(entry) = downloads.join_next().await {
+            record_entry(&mut manifest, progress, entry.into_diagnostic()?);
+        }
+
+        if !dry_run {
+            manifest::write_manifest(&output_dir, &manifest)?;
+        }
+        if let Some(archive) = self.archive.borrow_mut().as_mut() {
+            archive.write_paths(&manifest)?;
+        }
+
+        if let Some(err) = stream_error {
+            return Err(err.into());
+        }
+
+        if resume_range.is_some()
+            && !stopped_early
+            && !manifest
+                .iter()
+                .any(|entry| entry.status == Status::NotFetched)
+        {
+            resume_state::remove(&self.cache, &conversation.sync_key())?;
+        }
+
+        // only move the sync point forward once everything before it is downloaded
+        if !dry_run
+            && !stopped_early
+            && !has_failures(&manifest)
+            && let Some(newest) = &newest
+        {
+            sync_state::write(&self.cache, &conversation.sync_key(), newest)?;
+        }
+
+        Ok(manifest)
+    }
+
+    /// Whether the run has written as many bytes as `--max-total` allows. Downloads
+    /// already started when it is reached are finished, so it may be exceeded by up
+    /// to as many files as are downloaded at the same time.
+    fn budget_spent(&self) -> bool {
+        self.options
+            .max_total
+            .is_some_and(|max_total| self.progress.bytes() >= max_total)
+    }
+
+    /// How many attachments have been downloaded, toward the `--limit`.
+    fn limit_count(&self) -> usize {
+        self.progress.downloaded() - self.group_images.get()
+    }
+
+    /// Download the image of `group` into `output_dir`, as [`GROUP_IMAGE_NAME`],
+    /// if it has one, images are downloaded, and messages aren't filtered: it isn't
+    /// one of their attachments. Nor does it count toward the `--limit`, though
+    /// `false` is returned once the limit is reached, like [`Self::queue_download`].
+    async fn queue_group_image(
+        &self,
+        group: &Group,
+        output_dir: &Path,
+        downloads: &mut JoinSet<manifest::Entry>,
+        manifest: &mut Vec<manifest::Entry>,
+    ) -> miette::Result<bool> {
+        let options = &self.options;
+        let filtered = !options.from_user.is_empty()
+            || !options.text_contains.is_empty()
+            || options.regex.is_some()
+            || options.min_likes.is_some();
+        if filtered || !options.types.contains(&AttachmentKind::Image) {
+            return Ok(true);
+        }
+        let Some(url) = group.image_url.as_ref().filter(|url| !url.is_empty()) else {
+            return Ok(true);
+        };
+        let target = DownloadTarget {
+            dir: output_dir.to_path_buf(),
+            template: format!("{GROUP_IMAGE_NAME}.{{ext}}"),
+            variant: None,
+            fields: FilenameFields {
+                created_at: self.options.tz.convert(group.updated_at),
+                user: String::new(),
+                index: 0,
+                message_id: String::new(),
+            },
+            legacy_template: None,
+        };
+        let entry = manifest::Entry {
+            group: group.name.clone(),
+            message_id: String::new(),
+            created_at: None,
+            user: String::new(),
+            url: url.clone(),
+            path: None,
+            status: Status::Skipped,
+            size: None,
+            mislabeled_ext: None,
+            error: None,
+        };
+        let provisional_ext = model::ext_hint(url).map(Cow::Borrowed);
+        let queued = self
+            .queue_download(
+                downloads,
+                manifest,
+                url,
+                provisional_ext,
+                target,
+                entry,
+                None,
+            )
+            .await?;
+        // it is queued before any attachment, so is waited for here to tell it apart
+        while let Some(entry) = downloads.join_next().await {
+            let entry = entry.into_diagnostic()?;
+            if entry.status == Status::Downloaded {
+                self.group_images.set(self.group_images.get() + 1);
+            }
+            record_entry(manifest, &self.progress, entry);
+        }
+        Ok(queued)
+    }
+
+    /// Download the profile pictures of the members of `conversation` into an
+    /// `avatars` folder of `output_dir`, named by nickname and user id.
+    /// Returns `false` once the `--limit` is reached, like [`Self::queue_download`].
+    async fn queue_avatars(
+        &self,
+        conversation: &Conversation,
+        output_dir: &Path,
+        downloads: &mut JoinSet<manifest::Entry>,
+        manifest: &mut Vec<manifest::Entry>,
+    ) -> miette::Result<bool> {
+        let users = match conversation {
+            Conversation::Group(group) => group
+                .members
+                .iter()
+                .map(|member| (&member.user_id, &member.nickname, Some(&member.image_url)))
+                .collect::<Vec<_>>(),
+            Conversation::Chat(chat) => vec![(
+                &chat.other_user.id,
+                &chat.other_user.name,
+                chat.other_user.avatar_url.as_ref(),
+            )],
+        };
+
+        let avatars_dir = output_dir.join("avatars");
+        if !self.options.dry_run {
+            fs::create_dir_all(&avatars_dir).into_diagnostic()?;
+        }
+
+        for (user_id, nickname, url) in users {
+            // members who never set a picture have an empty or missing URL
+            let Some(url) = url.filter(|url| !url.is_empty()) else {
+                continue;
+            };
+            let target = DownloadTarget {
+                dir: avatars_dir.clone(),
+                template: "{user}.{ext}".to_string(),
+                variant: None,
+                fields: FilenameFields {
+                    created_at: self.options.tz.convert(Utc::now()),
+                    user: format!("{}_{user_id}", sanitize_filename(nickname)),
+                    index: 0,
+                    message_id: String::new(),
+                },
+                legacy_template: None,
+            };
+            let entry = manifest::Entry {
+                group: conversation.name().to_string(),
+                message_id: String::new(),
+                created_at: None,
+                user: nickname.clone(),
+                url: url.clone(),
+                path: None,
+                status: Status::Skipped,
+                size: None,
+                mislabeled_ext: None,
+                error: None,
+            };
+            let provisional_ext = model::ext_hint(url).map(Cow::Borrowed);
+            if !self
+                .queue_download(
+                    downloads,
+                    manifest,
+                    url,
+                    provisional_ext,
+                    target,
+                    entry,
+                    None,
+                )
+                .await?
+            {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Download `url` to `target` in the background, unless the file already exists,
+    /// recording the outcome in `entry`, and writing `sidecar` next to the file if given.
+    /// On a dry run, only report what would be done.
+    /// Returns `false` once the `--limit` is reached and nothing more should be downloaded.
+    #[allow(clippy::too_many_arguments)]
+    async fn queue_download(
+        &self,
+        downloads: &mut JoinSet<manifest::Entry>,
+        manifest: &mut Vec<manifest::Entry>,
+        url: &str,
+        provisional_ext: Option<Cow<'static, str>>,
+        target: DownloadTarget,
+        entry: manifest::Entry,
+        sidecar: Option<Sidecar>,
+    ) -> miette::Result<bool> {
+        let DownloadOptions {
+            dry_run,
+            overwrite,
+            limit,
+            detect_format,
+            max_size,
+            ..
+        } = self.options;
+        let client = &self.client;
+        let progress = &self.progress;
+        let semaphore = &self.semaphore;
+        let hashes = &self.hashes;
+
+        let existing_filepath = match provisional_ext.as_deref() {
+            Some(ext) => {
+                let filepath = target.filepath(ext);
+                match fs::exists(&filepath) {
+                    Ok(exists) => exists.then_some(filepath),
+                    Err(err) => {
+                        let entry = manifest::Entry {
+                            path: Some(filepath),
+                            status: Status::Failed,
+                            error: Some(err.to_string()),
+                            ..entry
+                        };
+                        self.failures
+                            .lock()
+                            .expect("not poisoned")
+                            .push(FailedDownload {
+                                entry: entry.clone(),
+                                target,
+                                provisional_ext: provisional_ext.map(Cow::into_owned),
+                            });
+                        record_entry(manifest, progress, entry);
+                        return Ok(true);
+                    }
+                }
+            }
+            None => None,
+        };
+        let existing_filepath = match existing_filepath {
+            None if !overwrite => provisional_ext
+                .as_deref()
+                .and_then(|ext| target.legacy_filepath(ext))
+                .filter(|filepath| filepath.exists()),
+            existing_filepath => existing_filepath,
+        };
+
+        if let Some(filepath) = &existing_filepath
+            && !overwrite
+        {
+            if dry_run {
+                self.report(format!("would skip existing file: {filepath:?}"));
+            } else {
+                info!("file already exists: {filepath:?}");
+            }
+            let entry = manifest::Entry {
+                path: Some(filepath.clone()),
+                ..entry
+            };
+            if !dry_run {
+                write_entry_sidecar(&entry, sidecar.as_ref());
+            }
+            record_entry(manifest, progress, entry);
+            return Ok(true);
+        }
+
+        if dry_run {
+            // without fetching, the URL is our only hint for the extension
+            let filepath = target.filepath(provisional_ext.as_deref().unwrap_or("*"));
+            if existing_filepath.is_some() {
+                self.report(format!("would overwrite file: {filepath:?} from {url}"));
+            } else {
+                self.report(format!("would download file: {filepath:?} from {url}"));
+            }
+            return Ok(true);
+        }
+
+        // once the budget is used up, the remaining attachments are only recorded,
+        // so that `retry-failures` can download them
+        if self.budget_spent() {
+            let entry = manifest::Entry {
+                status: Status::NotFetched,
+                ..entry
+            };
+            self.failures
+                .lock()
+                .expect("not poisoned")
+                .push(FailedDownload {
+                    entry: entry.clone(),
+                    target,
+                    provisional_ext: provisional_ext.map(Cow::into_owned),
+                });
+            record_entry(manifest, progress, entry);
+            return Ok(true);
+        }
+
+        // only start as many downloads as could still be needed to reach the limit,
+        // as some may turn out to be skipped
+        if let Some(limit) = limit {
+            while self.limit_count() + downloads.len() >= limit as usize {
+                let Some(entry) = downloads.join_next().await else {
+                    break;
+                };
+                record_entry(manifest, progress, entry.into_diagnostic()?);
+            }
+            if self.limit_count() >= limit as usize {
+                return Ok(false);
+            }
+        }
+
+        // acquire a permit before spawning so that we stop consuming the message
+        // stream (and therefore stop fetching pages) while the pool is saturated
+        let permit = semaphore.clone().acquire_owned().await.into_diagnostic()?;
+        let client = client.clone();
+        let multi = progress.multi().clone();
+        let hashes = hashes.clone();
+        let failures = self.failures.clone();
+        let url = url.to_string();
+        downloads.spawn(async move {
+            let result = download_file(
+                &client,
+                &multi,
+                &url,
+                &target,
+                provisional_ext.as_deref(),
+                FileOptions {
+                    overwrite,
+                    detect_format,
+                    max_size,
+                },
+                hashes.as_deref(),
+            )
+            .await;
+            drop(permit);
+            match result {
+                Ok(outcome) => {
+                    let entry = manifest::Entry {
+                        path: outcome.path,
+                        status: outcome.status,
+                        size: outcome.size,
+                        mislabeled_ext: outcome.mislabeled_ext,
+                        error: outcome.error,
+                        ..entry
+                    };
+                    write_entry_sidecar(&entry, sidecar.as_ref());
+                    entry
+                }
+                Err(err) => {
+                    let entry = manifest::Entry {
+                        status: Status::Failed,
+                        error: Some(format!("{err:?}")),
+                        ..entry
+                    };
+                    failures.lock().expect("not poisoned").push(FailedDownload {
+                        entry: entry.clone(),
+                        target,
+                        provisional_ext: provisional_ext.map(Cow::into_owned),
+                    });
+                    entry
+                }
+            }
+        });
+
+        Ok(true)
+    }
+
+    /// Print a `line` of output, or log it with `--json`, where stdout is kept for events.
+    fn report(&self, line: String) {
+        if self.options.json {
+            info!("{line}");
+        } else {
+            println!("{line}");
+        }
+    }
+}
+
+/// Add an attachment's `entry` to the `manifest` and the `progress` counts,
+/// reporting it if it failed. Unavailable attachments keep the reason in their `error`
+/// too, but were warned about already, as they aren't failures.
+pub fn record_entry(
+    manifest: &mut Vec<manifest::Entry>,
+    progress: &Progress,
+    entry: manifest::Entry,
+) {
+    progress.record(&entry);
+    if entry.status == Status::Failed
+        && let Some(error) = &entry.error
+    {
+        error!("failed to download file {}: {error}", entry.url);
+    }
+    manifest.push(entry);
+}
+
+/// Write the `sidecar`, if any, of the file saved for `entry`: replacing any sidecar
+/// of a file which was just downloaded, or adding a missing one for a file which
+/// already existed. Failing to is only warned about, the file itself is fine.
+fn write_entry_sidecar(entry: &manifest::Entry, sidecar: Option<&Sidecar>) {
+    let (Some(sidecar), Some(path)) = (sidecar, &entry.path) else {
+        return;
+    };
+    let overwrite = match entry.status {
+        Status::Downloaded => true,
+        Status::Skipped => false,
+        _ => return,
+    };
+    if let Err(err) = sidecar::write_sidecar(path, sidecar, overwrite) {
+        warn!("unable to write the sidecar of {path:?}: {err:?}");
+    }
+}
+
+/// Whether any attachment in the `manifest` failed to download, or wasn't fetched.
+fn has_failures(manifest: &[manifest::Entry]) -> bool {
+    manifest
+        .iter()
+        .any(|entry| matches!(entry.status, Status::Failed | Status::NotFetched))
+}
+
+/// The name, without extension, a group's own image is saved under in its directory.
+/// The underscore sorts it before the attachments.
+pub const GROUP_IMAGE_NAME: &str = "_group_image";
+
+/// The extension location files are saved with.
+const LOCATION_EXT: &str = "geojson";
+
+/// A location attachment, along with who shared it.
+struct Location<'a> {
+    lat: &'a str,
+    lon: &'a str,
+    name: &'a str,
+    user: &'a str,
+}
+
+/// Save a `location` to `target` as a GeoJSON point feature. An existing file is
+/// only replaced if `overwrite` is set.
+fn write_location(
+    target: &DownloadTarget,
+    location: &Location,
+    overwrite: bool,
+) -> miette::Result<(PathBuf, Status)> {
+    let filepath = target.filepath(LOCATION_EXT);
+    if !overwrite && fs::exists(&filepath).into_diagnostic()? {
+        info!("file already exists: {filepath:?}");
+        return Ok((filepath, Status::Skipped));
+    }
+
+    let coordinate = |value: &str| {
+        value
+            .parse::<f64>()
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Invalid coordinate {value:?}"))
+    };
+    let feature = serde_json::json!({
+        "type": "Feature",
+        "geometry": {
+            "type": "Point",
+            // GeoJSON puts longitude first
+            "coordinates": [coordinate(location.lon)?, coordinate(location.lat)?],
+        },
+        "properties": {
+            "name": location.name,
+            "user": location.user,
+            "message_id": target.fields.message_id,
+            "created_at": target.fields.created_at.to_rfc3339(),
+        },
+    });
+
+    info!("writing location: {filepath:?}");
+    if let Some(dir) = filepath.parent() {
+        fs::create_dir_all(dir).into_diagnostic()?;
+    }
+    let file = File::create(&filepath).into_diagnostic()?;
+    serde_json::to_writer_pretty(&file, &feature).into_diagnostic()?;
+    file.set_modified(target.fields.created_at.into())
+        .into_diagnostic()?;
+    Ok((filepath, Status::Downloaded))
+}
+
+/// What [`download_file`] did with a file.
+#[derive(Debug)]
+pub struct FileOutcome {
+    /// where the file was saved (or its duplicate), if its type could be determined
+    pub path: Option<PathBuf>,
+    pub status: Status,
+    /// how many bytes were written, if the file was downloaded
+    pub size: Option<u64>,
+    /// the extension the file would otherwise have had, if it was named by the
+    /// format of its content instead
+    pub mislabeled_ext: Option<String>,
+    /// why the file couldn't be downloaded, if it was unavailable
+    pub error: Option<String>,
+}
+
+impl FileOutcome {
+    fn new(path: Option<PathBuf>, status: Status) -> Self {
+        Self {
+            path,
+            status,
+            size: None,
+            mislabeled_ext: None,
+            error: None,
+        }
+    }
+}
+
+/// How [`download_file`] treats the file it downloads.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FileOptions {
+    /// replace the file if it already exists
+    pub overwrite: bool,
+    /// correct the file's extension if the start of its content shows it to be in
+    /// another format, see [`model::sniff_ext`]
+    pub detect_format: bool,
+    /// skip the file if its `Content-Length` is more than this many bytes
+    pub max_size: Option<u64>,
+}
+
+/// Download the file at `url`, writing it to `target` with an extension
+/// determined by the response's content type, or else by `provisional_ext`,
+/// as set out by `options`. With `hashes`, the file isn't kept if its content
+/// was already downloaded to another file.
+pub async fn download_file(
+    client: &Client,
+    multi: &MultiProgress,
+    url: &str,
+    target: &DownloadTarget,
+    provisional_ext: Option<&str>,
+    options: FileOptions,
+    hashes: Option<&Hashes>,
+) -> miette::Result<FileOutcome> {
+    let FileOptions {
+        overwrite,
+        detect_format,
+        max_size,
+    } = options;
+    let started_at = Instant::now();
+    let outcome = retry::with_retries(client.max_retries(), || async {
+        let mut response = client
+            .download_request(url)
+            .send()
+            .await
+            .map_err(Failure::from_reqwest)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error = Error::Download {
+                status,
+                url: url.to_string(),
+            };
+            if is_unavailable(status) {
+                warn!("{error}, it has expired or was removed, skipping");
+                return Ok(FileOutcome {
+                    error: Some(error.to_string()),
+                    ..FileOutcome::new(None, Status::Unavailable)
+                });
+            }
+            return Err(Failure::from_status(status, response.headers(), error));
+        }
+
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        let Some(mut ext) = model::resolve_ext(provisional_ext, content_type) else {
+            warn!("unrecognized file type, skipping: {url}");
+            return Ok(FileOutcome::new(None, Status::Skipped));
+        };
+
+        if let Some(max_size) = max_size
+            && let Some(len) = response.content_length()
+            && len > max_size
+        {
+            info!("{url} is {len} bytes, more than --max-size, skipping");
+            return Ok(FileOutcome::new(
+                Some(target.filepath(ext)),
+                Status::TooLarge,
+            ));
+        }
+
+        // the signature is in the first few bytes, which the first chunk holds
+        let mut first_chunk = None;
+        let mut mislabeled_ext = None;
+        if detect_format {
+            first_chunk = response.chunk().await.map_err(Failure::from_reqwest)?;
+            if let Some(sniffed) = first_chunk.as_deref().and_then(model::sniff_ext)
+                && !model::same_format(sniffed, ext)
+            {
+                info!("{url} is labeled {ext}, but is {sniffed}");
+                mislabeled_ext = Some(ext.to_string());
+                ext = sniffed;
+            }
+        }
+
+        let filepath = target.filepath(ext);
+        if fs::exists(&filepath)? {
+            if !overwrite {
+                info!("file already exists: {filepath:?}");
+                return Ok(FileOutcome::new(Some(filepath), Status::Skipped));
+            }
+            info!("overwriting file: {filepath:?}");
+        } else if !overwrite
+            && let Some(legacy_filepath) = target.legacy_filepath(ext)
+            && fs::exists(&legacy_filepath)?
+        {
+            info!("file already exists under its old name: {legacy_filepath:?}");
+            return Ok(FileOutcome::new(Some(legacy_filepath), Status::Skipped));
+        } else {
+            info!("downloading file: {filepath:?}");
+        }
+
+        let bar = Progress::download_bar(multi, &filepath, response.content_length());
+        // date the file by when it was posted, so that it sorts in chat order
+        let modified = target.fields.created_at.into();
+        let result =
+            write_response_atomically(response, first_chunk, &filepath, modified, &bar, hashes)
+                .await;
+        bar.finish_and_clear();
+        let (len, duplicate) = result?;
+        debug!("fetched {url} ({len} bytes) in {:?}", started_at.elapsed());
+        if let Some(duplicate) = duplicate {
+            info!("already downloaded as {duplicate:?}, not keeping {filepath:?}");
+            return Ok(FileOutcome {
+                mislabeled_ext,
+                ..FileOutcome::new(Some(duplicate), Status::Duplicate)
+            });
+        }
+        Ok(FileOutcome {
+            size: Some(len),
+            mislabeled_ext,
+            ..FileOutcome::new(Some(filepath), Status::Downloaded)
+        })
+    })
+    .await?;
+    Ok(outcome)
+}
+
+/// Whether a file download failing with `status` means the file is gone for good,
+/// as happens to the media of old messages, rather than that something went wrong.
+fn is_unavailable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::FORBIDDEN | StatusCode::NOT_FOUND | StatusCode::GONE
+    )
+}
+
+/// Stream the body of `response` to a temporary file next to `filepath`, then move
+/// it into place, so that a file at `filepath` is only ever complete and memory use
+/// doesn't grow with the file's size. The body starts with `first_chunk`, if it was
+/// already read. A body shorter or longer than the response's `Content-Length` is
+/// a failure, worth retrying. The file's modification time is set to
+/// `modified`. With `hashes`, the file is discarded instead if another file
+/// already has the same content. Returns the number of bytes written, and the
+/// other file if there is one.
+async fn write_response_atomically(
+    mut response: reqwest::Response,
+    first_chunk: Option<Bytes>,
+    filepath: &Path,
+    modified: SystemTime,
+    bar: &ProgressBar,
+    hashes: Option<&Hashes>,
+) -> Result<(u64, Option<PathBuf>), Failure> {
+    let mut temp_file = TempFile {
+        path: temp_filepath(filepath),
+        persisted: false,
+    };
+    let expected_len = response.content_length();
+
+    // the filename template may put files in subdirectories
+    if let Some(dir) = filepath.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = File::options()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&temp_file.path)?;
+    let mut len = 0;
+    let mut hasher = hashes.map(|_| Sha256::new());
+    let mut first_chunk = first_chunk;
+    while let Some(chunk) = match first_chunk.take() {
+        Some(chunk) => Some(chunk),
+        None => response.chunk().await.map_err(Failure::from_reqwest)?,
+    } {
+        file.write_all(&chunk)?;
+        if let Some(hasher) = &mut hasher {
+            hasher.update(&chunk);
+        }
+        len += chunk.len() as u64;
+        bar.inc(chunk.len() as u64);
+    }
+
+    // a dropped connection can end the body early, so don't keep a partial file
+    if let Some(expected_len) = expected_len
+        && len != expected_len
+    {
+        return Err(Failure::Transient(Error::IncompleteDownload {
+            received: len,
+            expected: expected_len,
+        }));
+    }
+
+    // a duplicate is discarded along with the temporary file
+    if let Some(hashes) = hashes
+        && let Some(hasher) = hasher
+        && let Some(duplicate) = hashes.claim(dedupe::hex_digest(hasher), filepath)
+    {
+        return Ok((len, Some(duplicate)));
+    }
+
+    file.set_modified(modified)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&temp_file.path, filepath)?;
+    temp_file.persisted = true;
+    Ok((len, None))
+}
+
+/// A temporary file which is removed when dropped, unless it was moved into place.
+/// This includes when its download is cancelled, e.g. by Ctrl-C.
+struct TempFile {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// The hidden temporary file a download to `filepath` is written to until it is complete.
+fn temp_filepath(filepath: &Path) -> PathBuf {
+    let filename = filepath.file_name().unwrap_or_default().to_string_lossy();
+    filepath.with_file_name(format!(".{filename}.part"))
+}
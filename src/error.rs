@@ -0,0 +1,150 @@
+use std::{fmt, io, path::PathBuf};
+
+use chrono::{DateTime, Utc};
+use miette::Diagnostic;
+use reqwest::StatusCode;
+
+use crate::config::API_TOKEN_ENV_VAR;
+
+/// The ways in which talking to GroupMe, or reading and writing the configuration
+/// and cache, can fail.
+#[derive(Debug, thiserror::Error, Diagnostic)]
+pub enum Error {
+    #[error(
+        "User configuration not found. Please use the `set-config` command first, or set the {API_TOKEN_ENV_VAR} environment variable."
+    )]
+    ConfigNotFound,
+
+    #[error("Must pick a target folder for image downloads.")]
+    NoImageDir,
+
+    /// `kind` being `cache` or `config`
+    #[error("Unable to locate user's {kind} directory.")]
+    DirNotFound { kind: &'static str },
+
+    #[error("Unable to write to directory {dir:?}")]
+    DirNotWritable {
+        dir: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error(
+        "Unable to read your API token from the OS keyring, please use the `set-config` command again"
+    )]
+    Keyring {
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[error("Your API token was rejected, your configuration has not been changed")]
+    TokenRejected {
+        #[source]
+        source: Box<Error>,
+    },
+
+    #[error("Invalid proxy URL {proxy:?} in your configuration")]
+    InvalidProxy {
+        proxy: String,
+        #[source]
+        source: reqwest::Error,
+    },
+
+    #[error("Invalid filename template {template:?} in your configuration, {reason}")]
+    InvalidFilenameTemplate {
+        template: String,
+        reason: &'static str,
+    },
+
+    #[error("{}: check your API token{}", StatusCode::UNAUTHORIZED, ApiDetails::suffix(.details))]
+    Unauthorized { details: Option<ApiDetails> },
+
+    /// GroupMe asked for fewer requests, and didn't let up within the retries.
+    #[error("{}: rate limited by GroupMe, try again later{}", StatusCode::TOO_MANY_REQUESTS, ApiDetails::suffix(.details))]
+    RateLimited { details: Option<ApiDetails> },
+
+    /// Any other unsuccessful response from the GroupMe API.
+    #[error("{status}: request to the GroupMe API failed{}", ApiDetails::suffix(.details))]
+    Api {
+        status: StatusCode,
+        details: Option<ApiDetails>,
+    },
+
+    /// A `304 Not Modified` response to a request which can't have one.
+    #[error("{}: unexpected response to {path}", StatusCode::NOT_MODIFIED)]
+    NotModified { path: String },
+
+    #[error("{status}: unable to download {url}")]
+    Download { status: StatusCode, url: String },
+
+    /// A body shorter or longer than the response's `Content-Length`.
+    #[error("received {received} of {expected} bytes, the download was cut short")]
+    IncompleteDownload { received: u64, expected: u64 },
+
+    #[error("File #{file_id} not found in group #{group_id}.")]
+    FileNotFound { group_id: String, file_id: String },
+
+    #[error("Message #{message_id} not found in group #{group_id}.")]
+    MessageNotFound {
+        group_id: String,
+        message_id: String,
+    },
+
+    #[error("Newest date {newest} must be later than oldest date {oldest}")]
+    InvalidDateRange {
+        newest: DateTime<Utc>,
+        oldest: DateTime<Utc>,
+    },
+
+    #[error("Gave up after {retries} retries")]
+    GaveUp {
+        retries: u32,
+        #[source]
+        source: Box<Error>,
+    },
+
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+
+    #[error(transparent)]
+    InvalidUrl(#[from] url::ParseError),
+
+    #[error(transparent)]
+    Json(#[from] serde_path_to_error::Error<serde_json::Error>),
+
+    #[error(transparent)]
+    Prompt(#[from] dialoguer::Error),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// A [`Result`](std::result::Result) failing with an [`Error`].
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// GroupMe's own details of an unsuccessful API response, from its body.
+#[derive(Clone, Debug)]
+pub struct ApiDetails {
+    pub code: i64,
+    pub errors: Vec<String>,
+}
+
+impl ApiDetails {
+    /// The `details`, if any, to append to an error message.
+    fn suffix(details: &Option<ApiDetails>) -> String {
+        details
+            .as_ref()
+            .map(|details| format!(" ({details})"))
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for ApiDetails {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "GroupMe code {}", self.code)?;
+        if !self.errors.is_empty() {
+            write!(f, ": {}", self.errors.join(", "))?;
+        }
+        Ok(())
+    }
+}
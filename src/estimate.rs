@@ -0,0 +1,192 @@
+use chrono::{DateTime, Utc};
+
+use crate::model::{AttachmentKind, GroupMessagesPage, Message};
+
+/// A guess at how much a download of a date range would fetch, made from the
+/// newest page of a group's messages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Estimate {
+    /// how many messages the group has in total
+    pub total_messages: u64,
+    /// how many messages were sent within the date range
+    pub messages: u64,
+    /// how many attachments of the chosen kinds were sent within the date range
+    pub attachments: u64,
+    /// whether the numbers were counted, rather than extrapolated
+    pub exact: bool,
+}
+
+/// Estimate how many messages and attachments of the given `types` were sent
+/// between `start` and `end`, given the newest `page` of a group created at
+/// `group_created_at`. Messages within the page are counted; the rest of the range,
+/// before the page, is extrapolated from how often messages were sent within it.
+pub fn estimate(
+    page: &GroupMessagesPage,
+    group_created_at: DateTime<Utc>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    types: &[AttachmentKind],
+) -> Estimate {
+    let total_messages = page.count.max(0) as u64;
+    let attachments = |message: &Message| {
+        message
+            .attachments
+            .iter()
+            .filter(|attachment| attachment.kind().is_some_and(|kind| types.contains(&kind)))
+            .count() as u64
+    };
+
+    let in_range = page
+        .messages
+        .iter()
+        .filter(|message| start <= message.created_at && message.created_at <= end);
+    let counted_messages = in_range.clone().count() as u64;
+    let counted_attachments = in_range.map(attachments).sum::<u64>();
+
+    // the page is newest first
+    let (Some(newest), Some(oldest)) = (page.messages.first(), page.messages.last()) else {
+        return Estimate {
+            total_messages,
+            messages: 0,
+            attachments: 0,
+            exact: true,
+        };
+    };
+    if page.messages.len() as u64 >= total_messages || start >= oldest.created_at {
+        return Estimate {
+            total_messages,
+            messages: counted_messages,
+            attachments: counted_attachments,
+            exact: true,
+        };
+    }
+
+    // the part of the range before the page, but after the group was created
+    let unseen = (end.min(oldest.created_at) - start.max(group_created_at))
+        .num_seconds()
+        .max(0) as f64;
+    let sampled = (newest.created_at - oldest.created_at).num_seconds().max(1) as f64;
+    let messages_per_sec = page.messages.len() as f64 / sampled;
+    let attachments_per_message =
+        page.messages.iter().map(attachments).sum::<u64>() as f64 / page.messages.len() as f64;
+
+    let unseen_messages = ((unseen * messages_per_sec).round() as u64)
+        .min(total_messages.saturating_sub(page.messages.len() as u64));
+    Estimate {
+        total_messages,
+        messages: counted_messages + unseen_messages,
+        attachments: counted_attachments
+            + (unseen_messages as f64 * attachments_per_message).round() as u64,
+        exact: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::model::MessageAttachment;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(secs, 0).unwrap()
+    }
+
+    fn message(created_at: i64, images: usize) -> Message {
+        Message {
+            id: created_at.to_string(),
+            source_guid: created_at.to_string(),
+            created_at: at(created_at),
+            user_id: "1".to_string(),
+            group_id: Some("1".to_string()),
+            recipient_id: None,
+            name: "user".to_string(),
+            avatar_url: None,
+            text: None,
+            system: false,
+            favorited_by: vec![],
+            attachments: (0..images)
+                .map(|_| MessageAttachment::Image {
+                    url: "https://i.groupme.com/1x1.png.abc".to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    /// A page of 10 messages, one every 10 seconds from 1000 to 1090, every other with an image.
+    fn page(count: i64) -> GroupMessagesPage {
+        GroupMessagesPage {
+            count,
+            messages: (0..10)
+                .rev()
+                .map(|i| message(1_000 + i * 10, (i % 2) as usize))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn counts_a_range_within_the_page() {
+        let estimate = estimate(
+            &page(1_000),
+            at(0),
+            at(1_040),
+            at(2_000),
+            &[AttachmentKind::Image],
+        );
+        assert_eq!(
+            estimate,
+            Estimate {
+                total_messages: 1_000,
+                messages: 6,
+                attachments: 3,
+                exact: true,
+            }
+        );
+    }
+
+    #[test]
+    fn counts_every_message_of_a_small_group() {
+        let estimate = estimate(&page(10), at(0), at(0), at(2_000), &[AttachmentKind::Image]);
+        assert_eq!(estimate.messages, 10);
+        assert!(estimate.exact);
+    }
+
+    #[test]
+    fn extrapolates_before_the_page() {
+        // 10 messages in 90 seconds, so about 100 more in the 900 seconds before the page
+        let estimate = estimate(
+            &page(1_000),
+            at(0),
+            at(100),
+            at(2_000),
+            &[AttachmentKind::Image],
+        );
+        assert_eq!(estimate.messages, 110);
+        assert_eq!(estimate.attachments, 55);
+        assert!(!estimate.exact);
+    }
+
+    #[test]
+    fn extrapolates_no_further_back_than_the_group() {
+        let estimate = estimate(
+            &page(1_000),
+            at(910),
+            at(0),
+            at(2_000),
+            &[AttachmentKind::Image],
+        );
+        assert_eq!(estimate.messages, 20);
+    }
+
+    #[test]
+    fn only_counts_the_chosen_kinds() {
+        let estimate = estimate(
+            &page(1_000),
+            at(0),
+            at(1_000),
+            at(2_000),
+            &[AttachmentKind::Video],
+        );
+        assert_eq!(estimate.attachments, 0);
+    }
+}
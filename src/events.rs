@@ -0,0 +1,71 @@
+//! Machine-readable output of a download run, for `download --json`.
+//!
+//! Each event is written to stdout as one line of JSON, tagged by its `event` field.
+
+use std::io::Write;
+
+use serde::Serialize;
+
+use crate::manifest::Entry;
+use crate::progress::Totals;
+
+/// Something which happened during a download run.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// an attachment is done, one way or another
+    Attachment(&'a Entry),
+    /// the run is over
+    Summary(Totals),
+}
+
+/// Write `event` to stdout as a line of JSON.
+pub fn emit(event: &Event) -> std::io::Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    serde_json::to_writer(&mut stdout, event)?;
+    writeln!(stdout)?;
+    stdout.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Status;
+
+    #[test]
+    fn attachment_events_are_tagged_entries() {
+        let entry = Entry {
+            group: "Friends".to_string(),
+            message_id: "1".to_string(),
+            created_at: None,
+            user: "Alice".to_string(),
+            url: "https://i.groupme.com/abc.jpeg".to_string(),
+            path: Some("Friends_2/photo.jpeg".into()),
+            status: Status::Downloaded,
+            size: Some(42),
+            mislabeled_ext: None,
+            error: None,
+        };
+        let value = serde_json::to_value(Event::Attachment(&entry)).unwrap();
+        assert_eq!(value["event"], "attachment");
+        assert_eq!(value["status"], "downloaded");
+        assert_eq!(value["path"], "Friends_2/photo.jpeg");
+        assert_eq!(value["size"], 42);
+    }
+
+    #[test]
+    fn summary_events_are_tagged_totals() {
+        let totals = Totals {
+            scanned: 10,
+            downloaded: 3,
+            skipped: 1,
+            failed: 0,
+            bytes: 1024,
+            elapsed_secs: 1.5,
+        };
+        let value = serde_json::to_value(Event::Summary(totals)).unwrap();
+        assert_eq!(value["event"], "summary");
+        assert_eq!(value["downloaded"], 3);
+        assert_eq!(value["bytes"], 1024);
+    }
+}
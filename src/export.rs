@@ -0,0 +1,404 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use miette::IntoDiagnostic;
+use serde::Serialize;
+
+use crate::model::{Message, MessageAttachment, Timestamp};
+
+/// The file format messages are exported in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// one JSON object per line (JSON Lines)
+    #[default]
+    Json,
+    Csv,
+}
+
+/// The order messages are exported in, instead of the order they are fetched in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportOrder {
+    /// chronological order, as in a transcript
+    OldestFirst,
+    NewestFirst,
+}
+
+/// A message as written to an export.
+#[derive(Debug, Serialize)]
+pub struct ExportRecord<'a> {
+    pub id: &'a str,
+    pub created_at: Timestamp,
+    /// the sender's nickname
+    pub user: &'a str,
+    pub text: Option<&'a str>,
+    pub attachment_urls: Vec<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<ReplyTo<'a>>,
+    /// the attachments without media, such as emoji, replies and locations,
+    /// if they are to be exported
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub other_attachments: Option<Vec<&'a MessageAttachment>>,
+}
+
+/// The message a message replies to.
+#[derive(Debug, Serialize)]
+pub struct ReplyTo<'a> {
+    pub id: &'a str,
+    pub user_id: &'a str,
+    /// whether the replied-to message was exported too, so that its sender and text are known
+    pub resolved: bool,
+    /// the replied-to message's sender, if it was resolved
+    pub user: Option<&'a str>,
+    /// the replied-to message's text, if it was resolved
+    pub text: Option<&'a str>,
+}
+
+impl<'a> ExportRecord<'a> {
+    pub fn new(message: &'a Message, user: &'a str) -> Self {
+        Self {
+            id: &message.id,
+            created_at: message.created_at,
+            user,
+            text: message.text.as_deref(),
+            attachment_urls: message
+                .attachments
+                .iter()
+                .filter_map(|attachment| match attachment {
+                    MessageAttachment::Image { url }
+                    | MessageAttachment::LinkedImage { url }
+                    | MessageAttachment::Video { url, .. } => Some(url.as_str()),
+                    _ => None,
+                })
+                .collect(),
+            reply_to: message
+                .attachments
+                .iter()
+                .find_map(|attachment| match attachment {
+                    MessageAttachment::Reply {
+                        user_id, reply_id, ..
+                    } => Some(ReplyTo {
+                        id: reply_id,
+                        user_id,
+                        resolved: false,
+                        user: None,
+                        text: None,
+                    }),
+                    _ => None,
+                }),
+            other_attachments: None,
+        }
+    }
+
+    /// Also export the attachments of `message` which have no media to download.
+    pub fn with_other_attachments(self, message: &'a Message) -> Self {
+        Self {
+            other_attachments: Some(
+                message
+                    .attachments
+                    .iter()
+                    .filter(|attachment| attachment.kind().is_none())
+                    .collect(),
+            ),
+            ..self
+        }
+    }
+}
+
+/// The id of the message `message` replies to, if it is a reply.
+fn reply_id(message: &Message) -> Option<&str> {
+    message
+        .attachments
+        .iter()
+        .find_map(|attachment| match attachment {
+            MessageAttachment::Reply { reply_id, .. } => Some(reply_id.as_str()),
+            _ => None,
+        })
+}
+
+/// Writes messages to an export file as they are streamed, or once they have all
+/// been streamed if they are to be exported in another order.
+///
+/// Replies are annotated with the message they reply to, if it is exported too.
+/// As messages are streamed newest first, a reply whose message wasn't streamed
+/// yet is kept back until [`Self::finish`], and then written after the others.
+pub struct Exporter {
+    format: ExportFormat,
+    other_attachments: bool,
+    order: Option<ExportOrder>,
+    /// the messages to write once they have all been streamed, with their senders:
+    /// all of them if exporting in another `order`, otherwise the replies to
+    /// messages which weren't streamed yet
+    buffered: Vec<(Message, String)>,
+    writer: BufWriter<File>,
+    /// the sender and text of the messages replied to, by id, once they are streamed
+    replied_to: HashMap<String, Option<(String, Option<String>)>>,
+}
+
+impl Exporter {
+    /// Create (or replace) the export file at `path`. With `other_attachments`,
+    /// the attachments without media are exported too, see [`ExportRecord::with_other_attachments`].
+    /// With an `order`, messages are kept in memory until [`Self::finish`], to be sorted.
+    pub fn create(
+        path: &Path,
+        format: ExportFormat,
+        other_attachments: bool,
+        order: Option<ExportOrder>,
+    ) -> miette::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path).into_diagnostic()?);
+        if format == ExportFormat::Csv {
+            write!(
+                writer,
+                "id,created_at,user,text,attachment_urls,reply_to_id,reply_to_resolved,reply_to_user,reply_to_text"
+            )
+            .into_diagnostic()?;
+            if other_attachments {
+                write!(writer, ",other_attachments").into_diagnostic()?;
+            }
+            writeln!(writer).into_diagnostic()?;
+        }
+        Ok(Self {
+            format,
+            other_attachments,
+            order,
+            buffered: Vec::new(),
+            writer,
+            replied_to: HashMap::new(),
+        })
+    }
+
+    /// Append `message`, sent by `user`, to the export. If it replies to another
+    /// message, it is annotated with that message's sender and text, once known.
+    pub fn write(&mut self, message: &Message, user: &str) -> miette::Result<()> {
+        if self.order.is_some() {
+            self.buffered.push((message.clone(), user.to_string()));
+            return Ok(());
+        }
+
+        if let Some(replied_to) = self.replied_to.get_mut(&message.id) {
+            *replied_to = Some((user.to_string(), message.text.clone()));
+        }
+        if let Some(reply_id) = reply_id(message)
+            && !matches!(self.replied_to.get(reply_id), Some(Some(_)))
+        {
+            self.replied_to.insert(reply_id.to_string(), None);
+            self.buffered.push((message.clone(), user.to_string()));
+            return Ok(());
+        }
+        self.write_record(message, user)
+    }
+
+    fn write_record(&mut self, message: &Message, user: &str) -> miette::Result<()> {
+        let mut record = ExportRecord::new(message, user);
+        if self.other_attachments {
+            record = record.with_other_attachments(message);
+        }
+        if let Some(reply_to) = &mut record.reply_to
+            && let Some(Some((user, text))) = self.replied_to.get(reply_to.id)
+        {
+            reply_to.resolved = true;
+            reply_to.user = Some(user);
+            reply_to.text = text.as_deref();
+        }
+
+        match self.format {
+            ExportFormat::Json => {
+                serde_json::to_writer(&mut self.writer, &record).into_diagnostic()?;
+                writeln!(self.writer).into_diagnostic()?;
+            }
+            ExportFormat::Csv => {
+                write!(
+                    self.writer,
+                    "{},{},{},{},{}",
+                    csv_field(record.id),
+                    record.created_at.to_rfc3339(),
+                    csv_field(record.user),
+                    csv_field(record.text.unwrap_or_default()),
+                    // several urls share a field, separated by spaces, which urls never contain
+                    csv_field(&record.attachment_urls.join(" ")),
+                )
+                .into_diagnostic()?;
+                let reply_to = record.reply_to.as_ref();
+                write!(
+                    self.writer,
+                    ",{},{},{},{}",
+                    csv_field(reply_to.map(|reply_to| reply_to.id).unwrap_or_default()),
+                    reply_to
+                        .map(|reply_to| reply_to.resolved.to_string())
+                        .unwrap_or_default(),
+                    csv_field(
+                        reply_to
+                            .and_then(|reply_to| reply_to.user)
+                            .unwrap_or_default()
+                    ),
+                    csv_field(
+                        reply_to
+                            .and_then(|reply_to| reply_to.text)
+                            .unwrap_or_default()
+                    ),
+                )
+                .into_diagnostic()?;
+                if let Some(other_attachments) = &record.other_attachments {
+                    // these have no common columns, so are kept as a JSON array
+                    let json = serde_json::to_string(other_attachments).into_diagnostic()?;
+                    write!(self.writer, ",{}", csv_field(&json)).into_diagnostic()?;
+                }
+                writeln!(self.writer).into_diagnostic()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write any messages kept back, sorted if they are to be, then flush the
+    /// export to disk. Replies to messages which weren't exported are marked unresolved.
+    pub fn finish(mut self) -> miette::Result<()> {
+        let mut buffered = std::mem::take(&mut self.buffered);
+        if let Some(order) = self.order {
+            // message ids are increasing numbers, which may be too large for an integer type
+            buffered.sort_by(|(a, _), (b, _)| {
+                (a.created_at, a.id.len(), &a.id).cmp(&(b.created_at, b.id.len(), &b.id))
+            });
+            if order == ExportOrder::NewestFirst {
+                buffered.reverse();
+            }
+
+            for (message, _) in &buffered {
+                if let Some(reply_id) = reply_id(message) {
+                    self.replied_to.insert(reply_id.to_string(), None);
+                }
+            }
+            for (message, user) in &buffered {
+                if let Some(replied_to) = self.replied_to.get_mut(&message.id) {
+                    *replied_to = Some((user.clone(), message.text.clone()));
+                }
+            }
+        }
+        for (message, user) in &buffered {
+            self.write_record(message, user)?;
+        }
+        self.writer.flush().into_diagnostic()
+    }
+}
+
+/// Quote `value` for a CSV field if it contains a separator, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use chrono::{TimeZone, Utc};
+    use serde_json::Value;
+
+    use super::*;
+
+    fn message(id: &str, created_at: i64, text: &str, reply_id: Option<&str>) -> Message {
+        Message {
+            id: id.to_string(),
+            source_guid: id.to_string(),
+            created_at: Utc.timestamp_opt(created_at, 0).unwrap(),
+            user_id: "2".to_string(),
+            group_id: Some("3".to_string()),
+            recipient_id: None,
+            name: "user".to_string(),
+            avatar_url: None,
+            text: Some(text.to_string()),
+            system: false,
+            favorited_by: vec![],
+            attachments: reply_id
+                .map(|reply_id| MessageAttachment::Reply {
+                    user_id: "2".to_string(),
+                    reply_id: reply_id.to_string(),
+                    base_reply_id: reply_id.to_string(),
+                })
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn replies_are_resolved_to_messages_streamed_after_them() {
+        let dir = std::env::temp_dir().join(format!("export-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("export.jsonl");
+
+        // newest first, as messages are streamed
+        let mut exporter = Exporter::create(&path, ExportFormat::Json, false, None).unwrap();
+        for (message, user) in [
+            (message("3", 300, "me too", Some("1")), "bob"),
+            (message("2", 200, "who?", Some("0")), "carol"),
+            (message("1", 100, "hello", None), "alice"),
+        ] {
+            exporter.write(&message, user).unwrap();
+        }
+        exporter.finish().unwrap();
+
+        let records = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // the replies are kept back until the messages they reply to could be streamed
+        let ids = records
+            .iter()
+            .map(|record| &record["id"])
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["1", "3", "2"]);
+        assert_eq!(
+            records[1]["reply_to"],
+            serde_json::json!({
+                "id": "1",
+                "user_id": "2",
+                "resolved": true,
+                "user": "alice",
+                "text": "hello",
+            })
+        );
+        // the message replied to wasn't exported
+        assert_eq!(records[2]["reply_to"]["resolved"], false);
+        assert_eq!(records[2]["reply_to"]["user"], Value::Null);
+    }
+
+    #[test]
+    fn sorted_replies_are_resolved_to_messages_sorted_after_them() {
+        let dir = std::env::temp_dir().join(format!("export-sorted-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("export.csv");
+
+        let mut exporter = Exporter::create(
+            &path,
+            ExportFormat::Csv,
+            false,
+            Some(ExportOrder::NewestFirst),
+        )
+        .unwrap();
+        exporter
+            .write(&message("1", 100, "hello", None), "alice")
+            .unwrap();
+        exporter
+            .write(&message("2", 200, "hi", Some("1")), "bob")
+            .unwrap();
+        exporter.finish().unwrap();
+
+        let csv = fs::read_to_string(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            csv.lines().skip(1).collect::<Vec<_>>(),
+            [
+                "2,1970-01-01T00:03:20+00:00,bob,hi,,1,true,alice,hello",
+                "1,1970-01-01T00:01:40+00:00,alice,hello,,,,,",
+            ]
+        );
+    }
+}
@@ -0,0 +1,45 @@
+use std::{fs, fs::File, io::ErrorKind, path::Path};
+
+use miette::IntoDiagnostic;
+use serde::{Deserialize, Serialize};
+
+use crate::{filename::DownloadTarget, manifest::Entry};
+
+/// The filename the failed downloads of a run are written to, in the download directory.
+pub const FAILURES_FILENAME: &str = "failures.json";
+
+/// An attachment which failed to download, with what is needed to try again.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FailedDownload {
+    /// the attachment's manifest entry, with its url, message id and error
+    pub entry: Entry,
+    pub target: DownloadTarget,
+    /// the extension to use if the response doesn't have a recognized content type
+    pub provisional_ext: Option<String>,
+}
+
+/// Write the `failures` of a download run to [`FAILURES_FILENAME`] in `dir`,
+/// replacing those of any previous run. Without failures, the file is removed.
+pub fn write_failures(dir: &Path, failures: &[FailedDownload]) -> miette::Result<()> {
+    let path = dir.join(FAILURES_FILENAME);
+    if failures.is_empty() {
+        return match fs::remove_file(path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err).into_diagnostic(),
+            _ => Ok(()),
+        };
+    }
+    let file = File::create(path).into_diagnostic()?;
+    serde_path_to_error::serialize(failures, &mut serde_json::Serializer::pretty(file))
+        .into_diagnostic()
+}
+
+/// Read the failed downloads of the last download run into `dir`, if there were any.
+pub fn read_failures(dir: &Path) -> miette::Result<Vec<FailedDownload>> {
+    let file = match File::open(dir.join(FAILURES_FILENAME)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).into_diagnostic(),
+    };
+    serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_reader(file))
+        .into_diagnostic()
+}
@@ -0,0 +1,323 @@
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Datelike, FixedOffset, Local, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+
+/// The filename template used when the user hasn't configured one. The message id
+/// keeps attachments of different messages sent in the same second apart.
+pub const DEFAULT_FILENAME_TEMPLATE: &str = "{date}T{time}.{message_id}.{index}.{user}.{ext}";
+
+/// The default filename template before it included the message id. Attachments
+/// already saved under it are treated as downloaded, so that they aren't
+/// downloaded again under their new name, see [`DownloadTarget::legacy_template`].
+pub const LEGACY_FILENAME_TEMPLATE: &str = "{date}T{time}.{index}.{user}.{ext}";
+
+/// The maximum length, in characters, of a sanitized filename component.
+const MAX_COMPONENT_LEN: usize = 64;
+
+/// Make `name` safe to embed in a filename on any OS: path separators, reserved
+/// characters and control characters are replaced, leading dots (hidden files)
+/// and trailing dots/spaces (invalid on Windows) are removed, and overly long
+/// names are truncated.
+pub fn sanitize_filename(name: &str) -> String {
+    let sanitized = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+
+    let sanitized = sanitized
+        .trim_start_matches(['.', ' '])
+        .chars()
+        .take(MAX_COMPONENT_LEN)
+        .collect::<String>();
+    let sanitized = sanitized.trim_end_matches(['.', ' ']);
+
+    if sanitized.is_empty() {
+        "unknown".to_string()
+    } else {
+        sanitized.to_string()
+    }
+}
+
+/// Prepend `prefix` to the filenames rendered from `template`, which is to the
+/// last component of the template should it put files in subdirectories,
+/// separated by either `/` or `\`.
+pub fn prefix_template(template: &str, prefix: &str) -> String {
+    let (dirs, filename) = template
+        .rsplit_once(['/', '\\'])
+        .map_or(("", template), |(dirs, filename)| {
+            (&template[..=dirs.len()], filename)
+        });
+    format!("{dirs}{prefix}{filename}")
+}
+
+/// Check that `template` renders paths within the directory files are saved in:
+/// relative ones, without `..` components. Returns why it doesn't otherwise.
+pub fn check_template(template: &str) -> Result<(), &'static str> {
+    if template.starts_with(['/', '\\']) || Path::new(template).is_absolute() {
+        return Err("it must be a relative path");
+    }
+    if template
+        .split(['/', '\\'])
+        .any(|component| component == "..")
+    {
+        return Err("it must not contain `..` components");
+    }
+    Ok(())
+}
+
+/// The time zone of the dates and times in filenames.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum FilenameTz {
+    /// this computer's time zone
+    #[default]
+    Local,
+    /// UTC, so that filenames are the same wherever they are downloaded
+    Utc,
+}
+
+impl FilenameTz {
+    /// Convert `time` to this time zone.
+    pub fn convert(self, time: DateTime<Utc>) -> DateTime<FixedOffset> {
+        match self {
+            Self::Local => time.with_timezone(&Local).fixed_offset(),
+            Self::Utc => time.fixed_offset(),
+        }
+    }
+}
+
+/// The values substituted into a filename template's placeholders.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FilenameFields {
+    pub created_at: DateTime<FixedOffset>,
+    /// the sender's sanitized nickname
+    pub user: String,
+    /// the attachment's index within its message
+    pub index: usize,
+    pub message_id: String,
+}
+
+impl FilenameFields {
+    /// Render `template`, substituting the `{date}`, `{time}`, `{user}`, `{index}`,
+    /// `{message_id}` and `{ext}` placeholders. Unknown placeholders are kept as is.
+    pub fn render(&self, template: &str, ext: &str) -> String {
+        let mut rendered = String::with_capacity(template.len());
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            rendered.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            let Some(end) = rest.find('}') else {
+                break;
+            };
+            let placeholder = &rest[1..end];
+            let date = &self.created_at;
+
+            match placeholder {
+                "date" => rendered.push_str(&format!(
+                    "{year}-{month:0>2}-{day:0>2}",
+                    year = date.year(),
+                    month = date.month(),
+                    day = date.day(),
+                )),
+                "time" => rendered.push_str(&format!(
+                    "{hour:0>2}_{min:0>2}_{sec:0>2}",
+                    hour = date.hour(),
+                    min = date.minute(),
+                    sec = date.second(),
+                )),
+                "user" => rendered.push_str(&self.user),
+                "index" => rendered.push_str(&self.index.to_string()),
+                "message_id" => rendered.push_str(&self.message_id),
+                "ext" => rendered.push_str(ext),
+                _ => rendered.push_str(&rest[..=end]),
+            }
+            rest = &rest[end + 1..];
+        }
+
+        rendered.push_str(rest);
+        rendered
+    }
+}
+
+/// Where to save a downloaded attachment, once its extension is known.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DownloadTarget {
+    pub dir: PathBuf,
+    pub template: String,
+    /// a variant of the attachment, e.g. a video's `preview` image, which is
+    /// saved alongside it with the variant inserted before the extension
+    pub variant: Option<Cow<'static, str>>,
+    pub fields: FilenameFields,
+    /// the template the attachment may have been saved with by an older version,
+    /// in which case it isn't downloaded again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub legacy_template: Option<String>,
+}
+
+impl DownloadTarget {
+    /// Where the attachment is saved, given its extension `ext`.
+    pub fn filepath(&self, ext: &str) -> PathBuf {
+        self.render(&self.template, ext)
+    }
+
+    /// Where an older version saved the attachment, given its extension `ext`,
+    /// if it used another template.
+    pub fn legacy_filepath(&self, ext: &str) -> Option<PathBuf> {
+        let template = self.legacy_template.as_deref()?;
+        Some(self.render(template, ext))
+    }
+
+    fn render(&self, template: &str, ext: &str) -> PathBuf {
+        let filename = match &self.variant {
+            Some(variant) => self.fields.render(template, &format!("{variant}.{ext}")),
+            None => self.fields.render(template, ext),
+        };
+        self.dir.join(filename)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn fields() -> FilenameFields {
+        FilenameFields {
+            created_at: Local
+                .with_ymd_and_hms(2024, 3, 1, 9, 5, 7)
+                .unwrap()
+                .fixed_offset(),
+            user: "alice".to_string(),
+            index: 1,
+            message_id: "123".to_string(),
+        }
+    }
+
+    #[test]
+    fn render_default_template() {
+        assert_eq!(
+            fields().render(DEFAULT_FILENAME_TEMPLATE, "png"),
+            "2024-03-01T09_05_07.123.1.alice.png"
+        );
+    }
+
+    #[test]
+    fn render_default_template_distinguishes_messages_sent_in_the_same_second() {
+        let first = DownloadTarget {
+            dir: PathBuf::from("group"),
+            template: DEFAULT_FILENAME_TEMPLATE.to_string(),
+            variant: None,
+            fields: FilenameFields {
+                index: 0,
+                ..fields()
+            },
+            legacy_template: None,
+        };
+        let second = DownloadTarget {
+            fields: FilenameFields {
+                message_id: "124".to_string(),
+                ..first.fields.clone()
+            },
+            ..first.clone()
+        };
+
+        assert_eq!(
+            first.filepath("jpg"),
+            Path::new("group/2024-03-01T09_05_07.123.0.alice.jpg")
+        );
+        assert_eq!(
+            second.filepath("jpg"),
+            Path::new("group/2024-03-01T09_05_07.124.0.alice.jpg")
+        );
+        assert_ne!(first.filepath("jpg"), second.filepath("jpg"));
+    }
+
+    #[test]
+    fn render_keeps_unknown_placeholders() {
+        assert_eq!(
+            fields().render("{user}-{message_id}-{nope}.{ext", "jpg"),
+            "alice-123-{nope}.{ext"
+        );
+    }
+
+    #[test]
+    fn prefix_template_prefixes_the_filename() {
+        assert_eq!(
+            prefix_template(DEFAULT_FILENAME_TEMPLATE, "reunion-"),
+            format!("reunion-{DEFAULT_FILENAME_TEMPLATE}")
+        );
+        assert_eq!(
+            prefix_template("{user}/{date}.{ext}", "reunion-"),
+            "{user}/reunion-{date}.{ext}"
+        );
+        assert_eq!(
+            prefix_template("{user}\\{date}.{ext}", "reunion-"),
+            "{user}\\reunion-{date}.{ext}"
+        );
+    }
+
+    #[test]
+    fn check_template_rejects_paths_outside_the_directory() {
+        assert!(check_template(DEFAULT_FILENAME_TEMPLATE).is_ok());
+        assert!(check_template("{user}/{date}.{ext}").is_ok());
+        assert!(check_template("/tmp/{date}.{ext}").is_err());
+        assert!(check_template("../{date}.{ext}").is_err());
+        assert!(check_template("{user}/../../{date}.{ext}").is_err());
+    }
+
+    #[test]
+    fn render_in_utc() {
+        let created_at = Utc.with_ymd_and_hms(2024, 3, 1, 23, 30, 0).unwrap();
+        let fields = FilenameFields {
+            created_at: FilenameTz::Utc.convert(created_at),
+            ..fields()
+        };
+        assert_eq!(fields.render("{date}T{time}", ""), "2024-03-01T23_30_00");
+    }
+
+    #[test]
+    fn download_target_round_trips_through_json() {
+        let target = DownloadTarget {
+            dir: PathBuf::from("group"),
+            template: DEFAULT_FILENAME_TEMPLATE.to_string(),
+            variant: Some(Cow::Borrowed("preview")),
+            fields: fields(),
+            legacy_template: Some(LEGACY_FILENAME_TEMPLATE.to_string()),
+        };
+        let json = serde_json::to_string(&target).unwrap();
+        let read: DownloadTarget = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.filepath("jpg"), target.filepath("jpg"));
+        assert_eq!(read.legacy_filepath("jpg"), target.legacy_filepath("jpg"));
+    }
+
+    #[test]
+    fn sanitize_filename_replaces_path_separators() {
+        assert_eq!(sanitize_filename("AC/DC \\ fan"), "AC_DC _ fan");
+    }
+
+    #[test]
+    fn sanitize_filename_strips_leading_dots() {
+        assert_eq!(sanitize_filename("..hidden"), "hidden");
+        assert_eq!(sanitize_filename("..."), "unknown");
+    }
+
+    #[test]
+    fn sanitize_filename_replaces_control_characters() {
+        assert_eq!(sanitize_filename("line\nbreak"), "line_break");
+    }
+
+    #[test]
+    fn sanitize_filename_truncates_long_names() {
+        assert_eq!(sanitize_filename(&"a".repeat(100)).len(), MAX_COMPONENT_LEN);
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local};
+use miette::IntoDiagnostic;
+
+use crate::manifest::{self, Status};
+
+/// The filename the gallery of a download directory is written to.
+pub const GALLERY_FILENAME: &str = "index.html";
+
+const IMAGE_EXTS: &[&str] = &["jpg", "jpeg", "png", "gif", "webp", "heic", "heif"];
+const VIDEO_EXTS: &[&str] = &["mp4", "mov", "webm"];
+
+/// A downloaded file shown in the gallery.
+struct Item {
+    filename: String,
+    created_at: DateTime<Local>,
+    user: Option<String>,
+    video: bool,
+}
+
+/// Write a self-contained [`GALLERY_FILENAME`] to `dir`, showing the images and
+/// videos in it in chronological order. Captions come from the manifest where
+/// it knows the file; otherwise the file's modification time is used, which
+/// downloads set to the time the message was sent.
+/// Returns the number of files in the gallery.
+pub fn write_gallery(dir: &Path, title: &str) -> miette::Result<usize> {
+    let known = manifest::read_manifest(dir)?
+        .into_iter()
+        .filter(|entry| entry.status != Status::Failed)
+        .filter_map(|entry| Some((entry.path.as_deref()?.file_name()?.to_owned(), entry)))
+        .collect::<HashMap<OsString, _>>();
+
+    let mut items = Vec::new();
+    for dir_entry in fs::read_dir(dir).into_diagnostic()? {
+        let dir_entry = dir_entry.into_diagnostic()?;
+        let path = PathBuf::from(dir_entry.file_name());
+        let Some(filename) = path.to_str() else {
+            continue;
+        };
+        // skips in-progress downloads too
+        if filename.starts_with('.') || filename.starts_with(crate::download::GROUP_IMAGE_NAME) {
+            continue;
+        }
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let video = VIDEO_EXTS.contains(&ext.as_str());
+        if !video && !IMAGE_EXTS.contains(&ext.as_str()) {
+            continue;
+        }
+
+        let entry = known.get(path.as_os_str());
+        let created_at = match entry.and_then(|entry| entry.created_at) {
+            Some(created_at) => created_at.with_timezone(&Local),
+            None => dir_entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .into_diagnostic()?
+                .into(),
+        };
+        items.push(Item {
+            filename: filename.to_string(),
+            created_at,
+            user: entry.map(|entry| entry.user.clone()),
+            video,
+        });
+    }
+    items.sort_by(|a, b| (a.created_at, &a.filename).cmp(&(b.created_at, &b.filename)));
+
+    fs::write(dir.join(GALLERY_FILENAME), render(title, &items)).into_diagnostic()?;
+    Ok(items.len())
+}
+
+fn render(title: &str, items: &[Item]) -> String {
+    let title = escape(title);
+    let mut html = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; margin: 1em; background: #fafafa; }}
+main {{ display: flex; flex-wrap: wrap; gap: 1em; }}
+figure {{ margin: 0; width: 320px; }}
+img, video {{ width: 100%; height: 240px; object-fit: cover; background: #ddd; }}
+figcaption {{ font-size: 0.85em; color: #555; }}
+</style>
+</head>
+<body>
+<h1>{title}</h1>
+<main>
+"#
+    );
+
+    for item in items {
+        let src = href(&item.filename);
+        let media = if item.video {
+            format!(r#"<video src="{src}" controls preload="metadata"></video>"#)
+        } else {
+            let alt = escape(&item.filename);
+            format!(r#"<a href="{src}"><img src="{src}" loading="lazy" alt="{alt}"></a>"#)
+        };
+        let caption = match &item.user {
+            Some(user) => format!(
+                "{} &middot; {}",
+                escape(user),
+                item.created_at.format("%Y-%m-%d %H:%M")
+            ),
+            None => item.created_at.format("%Y-%m-%d %H:%M").to_string(),
+        };
+        let _ = writeln!(
+            html,
+            "<figure>{media}<figcaption>{caption}</figcaption></figure>"
+        );
+    }
+
+    html.push_str("</main>\n</body>\n</html>\n");
+    html
+}
+
+/// Percent-encode `filename` for use as a relative URL.
+fn href(filename: &str) -> String {
+    filename
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// Escape `text` for use in HTML content and attribute values.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    fn entry(path: &Path, user: &str, status: Status) -> manifest::Entry {
+        manifest::Entry {
+            group: "group".to_string(),
+            message_id: "1".to_string(),
+            created_at: Some(Utc.with_ymd_and_hms(2024, 3, 1, 9, 5, 7).unwrap()),
+            user: user.to_string(),
+            url: "https://i.groupme.com/1".to_string(),
+            path: Some(path.to_path_buf()),
+            status,
+            size: None,
+            mislabeled_ext: None,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn write_gallery_links_images_and_videos() {
+        let dir = std::env::temp_dir().join(format!("gallery-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("a <b> & c.jpg");
+        let video = dir.join("video.mp4");
+        for path in [
+            &image,
+            &video,
+            &dir.join("notes.txt"),
+            &dir.join(".video.mp4.part"),
+        ] {
+            fs::write(path, b"").unwrap();
+        }
+        let entries = [
+            entry(&image, "Tom & \"Jerry\"", Status::Downloaded),
+            entry(&video, "alice", Status::Skipped),
+        ];
+        manifest::write_manifest(&dir, &entries).unwrap();
+
+        assert_eq!(write_gallery(&dir, "<Friends>").unwrap(), 2);
+
+        let html = fs::read_to_string(dir.join(GALLERY_FILENAME)).unwrap();
+        assert!(html.contains("<title>&lt;Friends&gt;</title>"));
+        assert!(html.contains(
+            r#"<a href="a%20%3Cb%3E%20%26%20c.jpg"><img src="a%20%3Cb%3E%20%26%20c.jpg" loading="lazy" alt="a &lt;b&gt; &amp; c.jpg"></a>"#
+        ));
+        assert!(html.contains("Tom &amp; &quot;Jerry&quot; &middot; "));
+        assert!(html.contains(r#"<video src="video.mp4" controls preload="metadata"></video>"#));
+        assert!(!html.contains("notes.txt"));
+        assert!(!html.contains(".part"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,27 @@
+//! Download the images and videos shared in GroupMe groups and direct messages.
+
+pub mod archive;
+pub mod bundle;
+pub mod cache;
+pub mod client;
+pub mod config;
+pub mod dates;
+pub mod dedupe;
+pub mod download;
+pub mod error;
+pub mod estimate;
+pub mod events;
+pub mod export;
+pub mod failures;
+pub mod filename;
+pub mod gallery;
+pub mod manifest;
+pub mod model;
+pub mod progress;
+pub mod rate_limit;
+pub mod resume_state;
+pub mod retry;
+pub mod sidecar;
+pub mod sync_state;
+pub mod token_store;
+pub mod transport;
@@ -1,45 +1,446 @@
-use std::collections::HashMap;
-use std::fs::{self, File};
-use std::io::Write;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use std::{fmt::Display, str::FromStr};
 
-use chrono::{DateTime, Datelike, Local, Months, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
-use clap::{Parser, Subcommand};
-use dialoguer::{Input, Password, Select};
-use futures_util::pin_mut;
+use chrono::{DateTime, Local, Utc};
+use clap::{CommandFactory, Parser, Subcommand};
+use dialoguer::{Confirm, Input, Select};
 use futures_util::stream::StreamExt;
-use miette::IntoDiagnostic;
+use indicatif::ProgressDrawTarget;
+use miette::{Context, IntoDiagnostic};
+use regex::Regex;
+use tracing::{Level, error, info, warn};
 
-pub mod cache;
-pub mod client;
-pub mod config;
-pub mod model;
-
-use cache::Cache;
-use client::Client;
-use config::Config;
+use groupme_download_rust::{
+    archive::Archive,
+    bundle,
+    cache::{self, Cache},
+    client::{self, Client},
+    config::{CONFIG_FILE_ENV_VAR, Config},
+    dates::{DATE_ARG_FORMATS, DateArg, round_month},
+    dedupe::{self, Hashes},
+    download::{
+        Conversation, DownloadOptions, DownloadRun, FileOptions, download_file, record_entry,
+    },
+    estimate,
+    events::{self, Event},
+    export::{ExportFormat, ExportOrder, Exporter},
+    failures::{self, FailedDownload},
+    filename::{FilenameTz, sanitize_filename},
+    gallery, manifest,
+    model::{AttachmentKind, Group, ImageSize},
+    progress::{self, LogWriter, Progress},
+    resume_state, retry, sync_state,
+};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
     #[command(subcommand)]
     command: Command,
+
+    /// Only report errors.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Report every request made and how long it took, and what each page of messages held.
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// How long to wait for GroupMe to respond before retrying a request, in seconds.
+    #[arg(long, global = true, value_name = "SECS", default_value_t = client::DEFAULT_TIMEOUT.as_secs(), value_parser = clap::value_parser!(u64).range(1..))]
+    timeout: u64,
+
+    /// Send requests with this `User-Agent`, instead of the configured one or
+    /// `groupme-downloader/<version>`.
+    #[arg(long, global = true, value_name = "USER_AGENT")]
+    user_agent: Option<String>,
+
+    /// Use a separately configured account, e.g. `--profile work`.
+    #[arg(long, global = true, value_parser = parse_profile)]
+    profile: Option<String>,
+
+    /// Read and write the configuration in this file, instead of in your config directory.
+    /// The API token is kept in the file rather than the OS keyring.
+    #[arg(long, global = true, value_name = "FILE", env = CONFIG_FILE_ENV_VAR)]
+    config: Option<PathBuf>,
+}
+
+/// Make a filename prefix safe to use, as any other part of a filename is. It can't
+/// be a path, so that files aren't saved outside of their group's directory.
+fn parse_prefix(prefix: &str) -> Result<String, String> {
+    if prefix.trim().is_empty() {
+        return Err("the prefix must not be empty".to_string());
+    }
+    if prefix.contains(['/', '\\']) || prefix == ".." {
+        return Err("the prefix must not be a path".to_string());
+    }
+    Ok(sanitize_filename(prefix))
+}
+
+/// Check that a profile name is safe to use in file names.
+fn parse_profile(name: &str) -> Result<String, String> {
+    if !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Ok(name.to_string())
+    } else {
+        Err("profile names may only contain letters, digits, `-` and `_`".to_string())
+    }
+}
+
+/// Parse a number of bytes, optionally followed by a unit such as `KB`, `MB` or `GiB`.
+fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        unit => return Err(format!("unknown unit `{unit}`, expected e.g. KB, MB or GB")),
+    };
+    let number = number
+        .parse::<f64>()
+        .map_err(|_| format!("`{size}` isn't a size, expected e.g. 500MB"))?;
+    Ok((number * multiplier as f64) as u64)
 }
 
 #[derive(Subcommand)]
+// parsed once per run, so the size of the download options doesn't matter
+#[allow(clippy::large_enum_variant)]
 enum Command {
     /// Update your user configuration: set your API Token, and choose your preferred download directory.
     SetConfig,
 
+    /// Change your preferred download directory, keeping the rest of your configuration.
+    SetDir {
+        /// The new download directory, otherwise you will be prompted to choose one.
+        dir: Option<PathBuf>,
+    },
+
+    /// Print where configuration, cached data and downloads are kept.
+    Paths,
+
+    /// Remove the current profile's cached data, such as the group list. Your configuration
+    /// is kept.
+    ClearCache {
+        /// Also forget what was downloaded from each group, which --since-last and --resume rely on,
+        /// and the content of the files downloaded, which --dedupe relies on.
+        #[arg(long)]
+        all: bool,
+    },
+
+    /// List the groups you are a member of (requires configuration to be set).
+    ListGroups {
+        /// Print the groups as JSON.
+        #[arg(long)]
+        json: bool,
+
+        /// Fetch the group list from GroupMe, even if a recently cached copy exists.
+        #[arg(long)]
+        refresh_groups: bool,
+
+        /// Also include the groups you have left.
+        #[arg(long)]
+        include_former: bool,
+    },
+
+    /// Write an `index.html` gallery of the downloaded images and videos.
+    Gallery {
+        /// The directory to write a gallery for, otherwise one is written for each group
+        /// directory in your download directory.
+        dir: Option<PathBuf>,
+    },
+
+    /// Print a message as JSON, including its attachments, e.g. to inspect one whose
+    /// id you found in an export. Download just its attachments with `download
+    /// --after-id` and `--before-id` set around it.
+    ShowMessage {
+        /// The id of the group the message was sent in.
+        group_id: String,
+
+        /// The id of the message.
+        message_id: String,
+    },
+
     /// Download images (requires configuration to be set).
     Download {
-        // set start date for the download, otherwise user will be prompted
+        // set start date for the download, otherwise user will be prompted.
+        // a bare date means midnight local time, an RFC 3339 offset is honored,
+        // and relative forms like `7d` or `last-month` count back from now
         #[arg(short, long)]
-        start: Option<NaiveDate>,
+        start: Option<DateArg>,
 
         // set end date for the download, otherwise user will be prompted
         #[arg(short, long)]
-        end: Option<NaiveDate>,
+        end: Option<DateArg>,
+
+        /// Only download messages sent after the message with this id, instead of a date
+        /// range. Takes precedence over --start and --end.
+        #[arg(long, value_name = "MESSAGE_ID", conflicts_with_all = ["dm", "all_groups", "since_last"])]
+        after_id: Option<String>,
+
+        /// Only download messages sent before the message with this id, instead of a date
+        /// range. Takes precedence over --start and --end.
+        #[arg(long, value_name = "MESSAGE_ID", conflicts_with_all = ["dm", "all_groups", "since_last"])]
+        before_id: Option<String>,
+
+        /// Maximum number of attachments to download at the same time.
+        #[arg(short, long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+        concurrency: u32,
+
+        /// Download from the group with this id, instead of being prompted to select one.
+        #[arg(long, conflicts_with_all = ["group_name", "dm"])]
+        group_id: Option<String>,
+
+        /// Download from every group you are a member of, each into its own directory.
+        #[arg(long, conflicts_with_all = ["group_id", "group_name", "dm", "flat"])]
+        all_groups: bool,
+
+        /// Download from the group with this name, instead of being prompted to select one.
+        #[arg(long, conflicts_with = "dm")]
+        group_name: Option<String>,
+
+        /// Download from your direct messages with the user with this id, instead of a group.
+        #[arg(long, value_name = "OTHER_USER_ID")]
+        dm: Option<String>,
+
+        /// Download into this directory this time, instead of your configured download directory.
+        #[arg(long, value_name = "DIR")]
+        out: Option<PathBuf>,
+
+        /// List the files that would be downloaded, without downloading them.
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Write the downloaded files into this zip file instead of a directory, adding
+        /// them to it if it exists. They are downloaded next to it first, then moved
+        /// into it, leaving their manifests and failures behind for `retry-failures --out`.
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["out", "dry_run", "estimate"])]
+        zip: Option<PathBuf>,
+
+        /// Estimate how many messages and attachments are in the date range, from the
+        /// newest page of messages, instead of downloading them.
+        #[arg(long, conflicts_with_all = ["since_last", "after_id", "before_id"])]
+        estimate: bool,
+
+        /// Don't ask before downloading a date range longer than --confirm-days.
+        #[arg(short, long)]
+        yes: bool,
+
+        /// Ask before downloading a date range longer than this many days.
+        #[arg(long, value_name = "DAYS", default_value_t = DEFAULT_CONFIRM_DAYS)]
+        confirm_days: u32,
+
+        /// Download files again, even if they already exist.
+        #[arg(long)]
+        overwrite: bool,
+
+        /// Don't keep files whose content was already downloaded under another name.
+        #[arg(long)]
+        dedupe: bool,
+
+        /// With --dedupe, first hash the files already in the download directory, so that
+        /// content there is recognized even if its files were renamed or moved. Such content
+        /// is still fetched to compare it, but not kept. Reading every file takes a while
+        /// for large directories.
+        #[arg(long, requires = "dedupe")]
+        scan_existing: bool,
+
+        /// Download directly into the download directory, instead of a subdirectory per group.
+        #[arg(long)]
+        flat: bool,
+
+        /// Stop after this many attachments have been downloaded, not counting skipped ones
+        /// or groups' images. Locations saved with --locations do count.
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+        limit: Option<u32>,
+
+        /// Keep going when an attachment fails to download, instead of stopping the download.
+        #[arg(long)]
+        continue_on_error: bool,
+
+        /// Which size of images to download. Videos are always downloaded as is.
+        #[arg(long, value_enum, default_value_t)]
+        image_size: ImageSize,
+
+        /// Prepend this to the name of every attachment's file, e.g. `reunion-2024-`, to tell
+        /// batches apart. Characters which aren't allowed in filenames are replaced.
+        #[arg(long, value_name = "PREFIX", value_parser = parse_prefix)]
+        prefix: Option<String>,
+
+        /// The time zone of the dates and times in filenames.
+        #[arg(long, value_enum, default_value_t)]
+        tz: FilenameTz,
+
+        /// Also download each video's preview image, saved next to the video as `<name>.preview.jpg`.
+        #[arg(long)]
+        video_previews: bool,
+
+        /// Also save shared locations, as GeoJSON files named like the other attachments.
+        #[arg(long)]
+        locations: bool,
+
+        /// Also download the members' profile pictures, into an `avatars` folder.
+        #[arg(long)]
+        avatars: bool,
+
+        /// Don't write a `<file>.json` sidecar next to each attachment downloaded from a message.
+        /// Sidecars hold the sender's nickname, the message's text and time, the group's name
+        /// and how many liked it, for photo managers to search by.
+        #[arg(long)]
+        no_sidecars: bool,
+
+        /// Name files by the format of their content, e.g. `.jpg` for a JPEG behind a `.png` URL,
+        /// when their URL or content type says otherwise.
+        #[arg(long)]
+        detect_format: bool,
+
+        /// Skip attachments larger than this, e.g. `500MB` or `2GiB`, going by the size
+        /// GroupMe gives for them. A number alone is in bytes.
+        #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+        max_size: Option<u64>,
+
+        /// Stop downloading once this much has been written in the run, e.g. `20GB`.
+        /// The attachments left are recorded as not fetched, for `retry-failures` to
+        /// download later. Downloads in progress are finished, so it may be exceeded
+        /// by up to --concurrency files.
+        #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+        max_total: Option<u64>,
+
+        /// Only download attachments sent by this user, given by user id or nickname.
+        /// Can be given more than once to download from any of several users.
+        #[arg(long, value_name = "USER_ID|NICKNAME")]
+        from_user: Vec<String>,
+
+        /// Only download attachments of messages whose text contains this, ignoring case.
+        /// Can be given more than once to download from messages containing any of them.
+        #[arg(long, value_name = "TEXT")]
+        text_contains: Vec<String>,
+
+        /// Only download attachments of messages whose text matches this regular expression,
+        /// e.g. `(?i)\bbeach(es)?\b`. Case sensitive, unless it starts with `(?i)`.
+        #[arg(long, value_name = "PATTERN", value_parser = Regex::new)]
+        regex: Option<Regex>,
+
+        /// Skip system messages, such as members being added or the group being renamed,
+        /// along with their attachments.
+        #[arg(long)]
+        skip_system: bool,
+
+        /// Only download attachments of messages liked by at least this many members.
+        #[arg(long, value_name = "N")]
+        min_likes: Option<u32>,
+
+        /// Only download attachments of this kind. Can be given more than once;
+        /// defaults to images and videos. Files shared in groups are only downloaded
+        /// when asked for, as each one takes an extra request.
+        #[arg(long = "type", value_enum, value_name = "KIND")]
+        types: Vec<AttachmentKind>,
+
+        /// How many times to retry a failed request before giving up.
+        #[arg(long, default_value_t = retry::DEFAULT_MAX_RETRIES)]
+        retries: u32,
+
+        /// How long to wait between fetching pages of messages, in milliseconds, give or
+        /// take a quarter at random so that groups downloaded at once don't fall into step.
+        #[arg(long, value_name = "MS", default_value_t = client::DEFAULT_PAGE_DELAY.as_millis() as u64)]
+        page_delay: u64,
+
+        /// How many messages of a group to fetch per page, at most 100. Smaller pages
+        /// take more requests. Direct messages always come 20 to a page.
+        #[arg(long, value_name = "N", default_value_t = client::MAX_MESSAGES_PER_PAGE as u32, value_parser = clap::value_parser!(u32).range(1..))]
+        page_size: u32,
+
+        /// Fetch pages without waiting in between, the same as `--page-delay 0`.
+        /// Quicker for small date ranges, but more likely to be rate limited by
+        /// GroupMe, in which case requests wait as long as it asks before retrying.
+        #[arg(long, conflicts_with = "page_delay")]
+        fast: bool,
+
+        /// Fetch the group list from GroupMe, even if a recently cached copy exists.
+        #[arg(long)]
+        refresh_groups: bool,
+
+        /// Also include the groups you have left.
+        #[arg(long)]
+        include_former: bool,
+
+        /// Only download messages sent since the newest message of the last download from
+        /// this group. Falls back to --start/--end (or prompting) the first time.
+        #[arg(long)]
+        since_last: bool,
+
+        /// Carry on from where an interrupted download of a date range from this group
+        /// stopped, over the same date range. Falls back to --start/--end (or prompting)
+        /// if there is nothing to resume.
+        #[arg(long, conflicts_with_all = ["since_last", "after_id", "before_id", "estimate"])]
+        resume: bool,
+
+        /// Also write each message's id, time, sender, text, attachment urls and the
+        /// message it replies to, if any, to this file. Replies to messages which
+        /// aren't exported too are marked unresolved.
+        #[arg(long, value_name = "PATH")]
+        export: Option<PathBuf>,
+
+        /// The format of the --export file.
+        #[arg(long, value_enum, default_value_t, requires = "export")]
+        format: ExportFormat,
+
+        /// Also write the attachments which have nothing to download, such as emoji,
+        /// replies, locations and bill splits, to the --export file.
+        #[arg(long, requires = "export")]
+        export_other_attachments: bool,
+
+        /// Also keep the messages downloaded from, and where their attachments were saved,
+        /// in this SQLite database, which is created if it doesn't exist. Messages already
+        /// in it are updated. Not written on a dry run.
+        #[arg(long, value_name = "PATH")]
+        db: Option<PathBuf>,
+
+        /// Write the --export file in this order, instead of the order messages are
+        /// fetched in. Keeps the exported messages in memory until the download is done.
+        #[arg(long, value_enum, requires = "export")]
+        export_order: Option<ExportOrder>,
+
+        /// Print a line of JSON to stdout for each attachment, with its status, path, size
+        /// and error, and a summary once done, instead of the usual output.
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Try again to download the files which failed to download in the last download run.
+    RetryFailures {
+        /// The directory the download run was into, otherwise your configured download directory.
+        #[arg(long, value_name = "DIR")]
+        out: Option<PathBuf>,
+
+        /// Maximum number of files to download at the same time.
+        #[arg(short, long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+        concurrency: u32,
+
+        /// Name files by the format of their content, as with `download --detect-format`.
+        #[arg(long)]
+        detect_format: bool,
+    },
+
+    /// Print a script completing this program's commands and options, for your shell
+    /// to load, e.g. `source <(groupme-download-rust completions bash)`.
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
     },
 }
 
@@ -47,136 +448,593 @@ enum Command {
 async fn main() -> miette::Result<()> {
     let args = Args::parse();
 
+    // progress is reported on stderr, so that stdout is kept for command output
+    let multi = progress::multi_progress(args.quiet);
+    let log_multi = multi.clone();
+    tracing_subscriber::fmt()
+        .with_writer(move || LogWriter(log_multi.clone()))
+        .with_max_level(if args.quiet {
+            Level::ERROR
+        } else if args.verbose {
+            Level::DEBUG
+        } else {
+            Level::INFO
+        })
+        .with_target(false)
+        .without_time()
+        .init();
+
+    let timeout = Duration::from_secs(args.timeout);
+    let user_agent = args.user_agent.as_deref();
+
     match args.command {
+        Command::Completions { shell } => {
+            let mut command = Args::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+        }
         Command::SetConfig => {
-            let api_token = Password::new()
-                .with_prompt("Type or paste your API token here")
-                .interact()
-                .into_diagnostic()?;
+            let cache = Cache::new(args.profile.clone(), args.config.clone())?;
+            let (_, me) = Config::prompt(&cache, timeout, user_agent).await?;
 
-            let config = Config::new(api_token)?;
-            Cache::new()?.write_config(&config)?;
-
-            println!("Your configuration has been saved, you can now download images.")
+            println!(
+                "Your configuration has been saved for {}, you can now download images.",
+                me.name
+            )
         }
-        Command::Download { start, end } => {
-            let cache = Cache::new()?;
+        Command::SetDir { dir } => {
+            let cache = Cache::new(args.profile.clone(), args.config.clone())?;
             let Some(config) = cache.read_config()? else {
                 miette::bail!(
                     "User configuration not found. Please use the `set-config` command first."
                 )
             };
 
-            let client = Client::new(cache, config.clone());
+            let image_dir = match dir {
+                Some(dir) => dir,
+                None => Config::pick_image_dir()?,
+            };
+            cache::check_writable(&image_dir)?;
 
-            let groups = client.get_all_groups().await?;
-            let groups_with_readable_names = groups
-                .into_iter()
-                .map(|group| (format!("{} (group id #{})", group.name, group.id), group))
-                .collect::<Vec<_>>();
-            let groups_readable_names = groups_with_readable_names
-                .iter()
-                .map(|(name, _)| name)
-                .collect::<Vec<_>>();
+            cache.write_config(&Config {
+                image_dir: image_dir.clone(),
+                ..config
+            })?;
+
+            println!("Your download directory has been changed to {image_dir:?}.")
+        }
+        Command::Paths => {
+            let cache = Cache::new(args.profile.clone(), args.config.clone())?;
+            let config = Config::resolve(cache.read_config()?)?;
+
+            println!("cache directory:    {:?}", cache.cache_dir());
+            println!("config directory:   {:?}", cache.config_dir());
+            println!("config file:        {:?}", cache.config_file_path());
+            match config {
+                Some(config) => println!("download directory: {:?}", config.image_dir),
+                None => println!("download directory: not configured"),
+            }
+        }
+        Command::ClearCache { all } => {
+            let cache = Cache::new(args.profile.clone(), args.config.clone())?;
+            let removed = cache.remove_cache_items(|filename| {
+                all || !(sync_state::is_sync_state_file(filename)
+                    || resume_state::is_resume_state_file(filename)
+                    || dedupe::is_hashes_file(filename))
+            })?;
+            println!(
+                "Removed {removed} cached file(s) from {:?}.",
+                cache.cache_dir()
+            );
+        }
+        Command::ListGroups {
+            json,
+            refresh_groups,
+            include_former,
+        } => {
+            let cache = Cache::new(args.profile.clone(), args.config.clone())?;
+            let config = Config::load_or_prompt(&cache, timeout, user_agent).await?;
+            let client = Client::new(cache, config, timeout, user_agent)?;
+
+            let groups = client.get_all_groups(refresh_groups).await?;
+            let former_groups = if include_former {
+                client.get_former_groups().await?
+            } else {
+                Vec::new()
+            };
+            if json {
+                let groups = groups.iter().chain(&former_groups).collect::<Vec<_>>();
+                serde_json::to_writer_pretty(std::io::stdout(), &groups).into_diagnostic()?;
+                println!();
+            } else {
+                let groups = groups.iter().map(|group| (group, ""));
+                let former_groups = former_groups.iter().map(|group| (group, ", left"));
+                for (group, former) in groups.chain(former_groups) {
+                    println!(
+                        "{} (group id #{}{former}): {} members, last active {}",
+                        group.name,
+                        group.id,
+                        group.members.len(),
+                        group
+                            .updated_at
+                            .with_timezone(&Local)
+                            .format("%Y-%m-%d %H:%M"),
+                    );
+                }
+            }
+        }
+        Command::ShowMessage {
+            group_id,
+            message_id,
+        } => {
+            let cache = Cache::new(args.profile.clone(), args.config.clone())?;
+            let config = Config::load_or_prompt(&cache, timeout, user_agent).await?;
+            let client = Client::new(cache, config, timeout, user_agent)?;
 
-            let group_idx = Select::new()
-                .with_prompt("Select a group to download images from")
-                .items(&groups_readable_names)
-                .default(0)
-                .interact()
-                .into_diagnostic()?;
-
-            let (_, group) = groups_with_readable_names
-                .get(group_idx)
-                .expect("access is checked by Select");
-
-            let group_users = group
-                .members
-                .iter()
-                .map(|user| (&user.user_id, user))
-                .collect::<HashMap<_, _>>();
-
-            let now = Local::now();
-            let start_date = if let Some(start_date) = start {
-                start_date
-                    .and_time(NaiveTime::default())
-                    .and_local_timezone(Local)
-                    .earliest()
-                    .expect("Unable to select a start date")
+            let message = client.get_message(&group_id, &message_id).await?;
+            serde_json::to_writer_pretty(std::io::stdout(), &message).into_diagnostic()?;
+            println!();
+        }
+        Command::Gallery { dir } => {
+            let dirs = match dir {
+                Some(dir) => vec![dir],
+                None => {
+                    let image_dir =
+                        Config::load(&Cache::new(args.profile.clone(), args.config.clone())?)?
+                            .image_dir;
+                    let mut dirs = vec![];
+                    for entry in fs::read_dir(&image_dir).into_diagnostic()? {
+                        let path = entry.into_diagnostic()?.path();
+                        if path.join(manifest::MANIFEST_FILENAME).is_file() {
+                            dirs.push(path);
+                        }
+                    }
+                    // a --flat download keeps its manifest in the download directory itself
+                    if image_dir.join(manifest::MANIFEST_FILENAME).is_file() {
+                        dirs.push(image_dir);
+                    }
+                    dirs
+                }
+            };
+            if dirs.is_empty() {
+                miette::bail!("No downloads found, use the `download` command first.");
+            }
+
+            for dir in dirs {
+                let title = dir
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| "GroupMe".to_string());
+                let count = gallery::write_gallery(&dir, &title)?;
+                println!(
+                    "Wrote a gallery of {count} file(s) to {:?}",
+                    dir.join(gallery::GALLERY_FILENAME)
+                );
+            }
+        }
+        Command::Download {
+            start,
+            end,
+            after_id,
+            before_id,
+            concurrency,
+            group_id,
+            group_name,
+            all_groups,
+            dm,
+            out,
+            dry_run,
+            zip,
+            estimate,
+            yes,
+            confirm_days,
+            overwrite,
+            dedupe,
+            scan_existing,
+            flat,
+            limit,
+            continue_on_error,
+            image_size,
+            tz,
+            prefix,
+            video_previews,
+            locations,
+            avatars,
+            no_sidecars,
+            detect_format,
+            max_size,
+            max_total,
+            from_user,
+            text_contains,
+            regex,
+            skip_system,
+            min_likes,
+            types,
+            retries,
+            page_delay,
+            page_size,
+            fast,
+            refresh_groups,
+            include_former,
+            since_last,
+            resume,
+            export,
+            format,
+            export_other_attachments,
+            export_order,
+            db,
+            json,
+        } => {
+            let cache = Cache::new(args.profile.clone(), args.config.clone())?;
+            let config = Config::load_or_prompt(&cache, timeout, user_agent).await?;
+            let config = match out.or_else(|| zip.as_deref().map(bundle::staging_dir)) {
+                Some(image_dir) => Config {
+                    image_dir,
+                    ..config
+                },
+                None => config,
+            };
+            if !dry_run && !estimate {
+                ensure_image_dir(&config.image_dir)?;
+            }
+
+            let client = Client::new(cache.clone(), config.clone(), timeout, user_agent)?
+                .with_max_retries(retries)
+                .with_messages_per_page(page_size as usize)
+                .with_page_delay(if fast {
+                    Duration::ZERO
+                } else {
+                    Duration::from_millis(page_delay)
+                });
+
+            let conversations = if let Some(other_user_id) = dm {
+                let chats = client.get_all_chats().await?;
+                let chat = chats
+                    .into_iter()
+                    .find(|chat| chat.other_user.id == other_user_id)
+                    .ok_or_else(|| {
+                        miette::miette!("No direct messages found with user id #{other_user_id}.")
+                    })?;
+                vec![Conversation::Chat(chat)]
+            } else if all_groups {
+                let groups = get_groups(&client, refresh_groups, include_former).await?;
+                groups.into_iter().map(Conversation::Group).collect()
+            } else {
+                let groups = get_groups(&client, refresh_groups, include_former).await?;
+                vec![Conversation::Group(select_group(
+                    groups, group_id, group_name,
+                )?)]
+            };
+
+            let mut last_syncs = Vec::with_capacity(conversations.len());
+            for conversation in &conversations {
+                let last_sync = if since_last {
+                    let last_sync = sync_state::read(&cache, &conversation.sync_key())?;
+                    if last_sync.is_none() {
+                        info!(
+                            "nothing was downloaded from {} before, downloading a date range",
+                            conversation.name()
+                        );
+                    }
+                    last_sync
+                } else {
+                    None
+                };
+                last_syncs.push(last_sync);
+            }
+
+            let mut resumes = Vec::with_capacity(conversations.len());
+            for conversation in &conversations {
+                let resume = if resume {
+                    let resume = resume_state::read(&cache, &conversation.sync_key())?;
+                    if resume.is_none() {
+                        info!(
+                            "no interrupted download from {} to resume, downloading a date range",
+                            conversation.name()
+                        );
+                    }
+                    resume
+                } else {
+                    None
+                };
+                resumes.push(resume);
+            }
+
+            let id_range =
+                (after_id.is_some() || before_id.is_some()).then_some((after_id, before_id));
+            if id_range.is_some() && (start.is_some() || end.is_some()) {
+                warn!("downloading between message ids, ignoring --start and --end");
+            }
+
+            // only ask for dates if there is a conversation to use them for
+            let date_range = if id_range.is_none()
+                && last_syncs
+                    .iter()
+                    .zip(&resumes)
+                    .any(|(last_sync, resume)| last_sync.is_none() && resume.is_none())
+            {
+                let now = Local::now();
+                let start_date = if let Some(start_date) = start {
+                    start_date
+                        .resolve()
+                        .ok_or_else(|| miette::miette!("Unable to select a start date"))?
+                } else {
+                    prompt_date(
+                        "Enter a start date",
+                        round_month(now, -1)
+                            .ok_or_else(|| miette::miette!("Unable to select a start date"))?,
+                    )?
+                };
+
+                let end_date = if let Some(end_date) = end {
+                    end_date
+                        .resolve()
+                        .ok_or_else(|| miette::miette!("Unable to select an end date"))?
+                } else {
+                    prompt_date(
+                        "Enter an end date",
+                        round_month(now, 0)
+                            .ok_or_else(|| miette::miette!("Unable to select an end date"))?,
+                    )?
+                };
+                Some((start_date, end_date))
+            } else {
+                None
+            };
+
+            if let Some((start_date, end_date)) = date_range {
+                check_date_range(&conversations, start_date, end_date, Local::now());
+                let confirmed = yes
+                    || dry_run
+                    || estimate
+                    || confirm_date_range(start_date, end_date, confirm_days)?;
+                if !confirmed {
+                    println!("Nothing was downloaded.");
+                    return Ok(());
+                }
+            }
+
+            let types = if types.is_empty() {
+                vec![AttachmentKind::Image, AttachmentKind::Video]
             } else {
-                prompt_date(
-                    "Enter a start date",
-                    round_month(now, -1)
-                        .ok_or_else(|| miette::miette!("Unable to select a start date"))?,
-                )?
+                types
             };
 
-            let end_date = if let Some(end_date) = end {
-                end_date
-                    .and_time(NaiveTime::default())
-                    .and_local_timezone(Local)
-                    .earliest()
-                    .expect("Unable to select an end date")
+            if estimate {
+                let (start_date, end_date) =
+                    date_range.expect("a date range is chosen without --since-last");
+                for conversation in &conversations {
+                    print_estimate(
+                        &client,
+                        conversation,
+                        start_date.to_utc(),
+                        end_date.to_utc(),
+                        &types,
+                    )
+                    .await?;
+                }
+                return Ok(());
+            }
+
+            let hashes = if dedupe {
+                let hashes = Hashes::load(&cache)?;
+                if scan_existing && fs::exists(&config.image_dir).into_diagnostic()? {
+                    info!("hashing the files in {:?}", config.image_dir);
+                    let hashed = hashes.index_dir(&config.image_dir)?;
+                    info!("hashed {hashed} file(s) not downloaded with --dedupe before");
+                }
+                Some(Arc::new(hashes))
             } else {
-                prompt_date(
-                    "Enter an end date",
-                    round_month(now, 0)
-                        .ok_or_else(|| miette::miette!("Unable to select an end date"))?,
-                )?
+                None
             };
 
-            let messages = client
-                .get_messages(end_date.to_utc(), start_date.to_utc(), group.id.to_string())
-                .await?;
-
-            pin_mut!(messages);
-            while let Some(message) = messages.next().await {
-                let message = message?;
-                let user_name = group_users
-                    .get(&message.user_id)
-                    .map(|user| user.nickname.as_ref())
-                    .unwrap_or_else(|| "unknown");
-
-                let date = message.created_at.with_timezone(&Local);
-
-                for (index, attachment) in message.attachments.iter().enumerate() {
-                    let Some((url, ext)) = attachment.get_download_url_and_ext() else {
-                        continue;
-                    };
-
-                    let filename = format!(
-                        "{year}-{month:0>2}-{day:0>2}T{hour:0>2}_{min:0>2}_{sec:0>2}.{index}.{user_name}.{ext}",
-                        year = date.year(),
-                        month = date.month(),
-                        day = date.day(),
-                        hour = date.hour(),
-                        min = date.minute(),
-                        sec = date.second()
+            if dry_run {
+                // the progress bar would get in the way of the listed files
+                multi.set_draw_target(ProgressDrawTarget::hidden());
+            }
+
+            let exporter = export
+                .map(|path| Exporter::create(&path, format, export_other_attachments, export_order))
+                .transpose()?;
+            let options = DownloadOptions {
+                concurrency,
+                dry_run,
+                overwrite,
+                flat,
+                limit,
+                continue_on_error,
+                image_size,
+                tz,
+                prefix: prefix.unwrap_or_default(),
+                video_previews,
+                locations,
+                avatars,
+                sidecars: !no_sidecars,
+                detect_format,
+                max_size,
+                max_total,
+                json,
+                id_range,
+                from_user,
+                text_contains: text_contains
+                    .iter()
+                    .map(|text| text.to_lowercase())
+                    .collect(),
+                regex,
+                skip_system,
+                min_likes,
+                types,
+            };
+            let run = DownloadRun::new(client, &config, options, Progress::new(&multi, json))
+                .with_hashes(hashes)
+                .with_exporter(exporter)
+                .with_archive(
+                    db.filter(|_| !dry_run)
+                        .map(|path| Archive::open(&path))
+                        .transpose()?,
+                );
+
+            let conversation_count = conversations.len();
+            let downloads =
+                futures_util::stream::iter(conversations.into_iter().zip(last_syncs).zip(resumes))
+                    .map(|((conversation, last_sync), resume)| async {
+                        let name = conversation.name().to_string();
+                        let result = run
+                            .download_conversation(conversation, last_sync, resume, date_range)
+                            .await;
+                        (name, result)
+                    })
+                    .buffer_unordered(GROUP_CONCURRENCY)
+                    .collect::<Vec<_>>();
+            // on Ctrl-C the downloads are dropped, cancelling those in progress and
+            // removing their partial files, and the run is wrapped up as usual
+            let results = tokio::select! {
+                results = downloads => Some(results),
+                Ok(()) = tokio::signal::ctrl_c() => None,
+            };
+            let interrupted = results.is_none();
+
+            run.progress.finish();
+            info!("{}", run.progress.summary());
+            if json {
+                events::emit(&Event::Summary(run.progress.totals())).into_diagnostic()?;
+            }
+
+            if let Some(hashes) = &run.hashes
+                && !dry_run
+            {
+                hashes.save(&cache)?;
+            }
+            if let Some(exporter) = run.exporter.into_inner() {
+                exporter.finish()?;
+            }
+            let retry_failures = match &zip {
+                Some(_) => format!("retry-failures --out {:?}", config.image_dir),
+                None => "retry-failures".to_string(),
+            };
+            if !dry_run {
+                let failed = run.failures.lock().expect("not poisoned");
+                failures::write_failures(&config.image_dir, &failed)?;
+                let not_fetched = failed
+                    .iter()
+                    .filter(|failed| failed.entry.status == manifest::Status::NotFetched)
+                    .count();
+                if not_fetched > 0 {
+                    warn!(
+                        "--max-total reached, {not_fetched} attachment(s) were not fetched, use the `{retry_failures}` command to download them."
                     );
-                    let filepath = config.image_dir.join(filename);
+                }
+            }
+            // even if interrupted, so that what was downloaded isn't left behind
+            if let Some(zip) = &zip {
+                let count = bundle::write_zip(&config.image_dir, zip)?;
+                info!("wrote {count} file(s) to {zip:?}");
+            }
+            if interrupted {
+                miette::bail!("Interrupted, the files being downloaded were discarded.");
+            }
+
+            let mut errors = results
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|(name, result)| Some((name, result.err()?)))
+                .collect::<Vec<_>>();
+            if conversation_count == 1
+                && let Some((_, err)) = errors.pop()
+            {
+                return Err(err);
+            }
+            for (name, err) in &errors {
+                error!("unable to download from {name}: {err:?}");
+            }
+
+            let failures = run.progress.failed();
+            if failures > 0 && !continue_on_error {
+                miette::bail!(
+                    "Stopped after a file failed to download, use --continue-on-error to download the remaining files."
+                );
+            }
+            if failures > 0 {
+                miette::bail!(
+                    "{failures} file(s) failed to download, use the `{retry_failures}` command to try them again."
+                );
+            }
+            if !errors.is_empty() {
+                miette::bail!(
+                    "Unable to download from {} of {conversation_count} groups.",
+                    errors.len()
+                );
+            }
+        }
+        Command::RetryFailures {
+            out,
+            concurrency,
+            detect_format,
+        } => {
+            let cache = Cache::new(args.profile.clone(), args.config.clone())?;
+            let config = Config::load(&cache)?;
+            let dir = out.unwrap_or_else(|| config.image_dir.clone());
 
-                    if fs::exists(&filepath).into_diagnostic()? {
-                        println!("file already exists: {filepath:?}");
-                        continue;
+            let failed = failures::read_failures(&dir)?;
+            if failed.is_empty() {
+                println!("No failed downloads found in {dir:?}.");
+                return Ok(());
+            }
+
+            let client = Client::new(cache, config, timeout, user_agent)?;
+            let progress = Progress::new(&multi, false);
+            let mut results = futures_util::stream::iter(failed)
+                .map(|failed| async {
+                    let result = download_file(
+                        &client,
+                        &multi,
+                        &failed.entry.url,
+                        &failed.target,
+                        failed.provisional_ext.as_deref(),
+                        FileOptions {
+                            detect_format,
+                            ..FileOptions::default()
+                        },
+                        None,
+                    )
+                    .await;
+                    (failed, result)
+                })
+                .buffer_unordered(concurrency as usize);
+
+            let mut entries = Vec::new();
+            let mut remaining = Vec::new();
+            while let Some((failed, result)) = results.next().await {
+                let entry = match result {
+                    Ok(outcome) => manifest::Entry {
+                        path: outcome.path,
+                        status: outcome.status,
+                        size: outcome.size,
+                        mislabeled_ext: outcome.mislabeled_ext,
+                        error: outcome.error,
+                        ..failed.entry
+                    },
+                    Err(err) => {
+                        let entry = manifest::Entry {
+                            status: manifest::Status::Failed,
+                            error: Some(format!("{err:?}")),
+                            ..failed.entry
+                        };
+                        remaining.push(FailedDownload {
+                            entry: entry.clone(),
+                            ..failed
+                        });
+                        entry
                     }
-                    println!("downloading file: {filepath:?}");
-
-                    let bytes = reqwest::get(url)
-                        .await
-                        .into_diagnostic()?
-                        .bytes()
-                        .await
-                        .into_diagnostic()?;
-
-                    let mut file = File::options()
-                        .create(true)
-                        .write(true)
-                        .truncate(true)
-                        .open(filepath)
-                        .into_diagnostic()?;
-
-                    file.write_all(&bytes).into_diagnostic()?;
-                }
+                };
+                record_entry(&mut entries, &progress, entry);
+            }
+
+            progress.finish();
+            info!("{}", progress.summary());
+            failures::write_failures(&dir, &remaining)?;
+            if !remaining.is_empty() {
+                miette::bail!("{} file(s) still failed to download.", remaining.len());
             }
         }
     }
@@ -184,41 +1042,210 @@ async fn main() -> miette::Result<()> {
     Ok(())
 }
 
-/// Prompt the user for a YYYY-MM-DD date.
+/// How many groups are downloaded from at the same time with `--all-groups`.
+/// Their API requests are spaced out together, so more wouldn't be much faster.
+const GROUP_CONCURRENCY: usize = 2;
+
+/// How many days a date range may span before asking whether to download it, unless configured otherwise.
+const DEFAULT_CONFIRM_DAYS: u32 = 180;
+
+/// Print an [`estimate::Estimate`] of how much downloading the attachments of
+/// `types` sent in `conversation` between `start` and `end` would fetch.
+async fn print_estimate(
+    client: &Client,
+    conversation: &Conversation,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    types: &[AttachmentKind],
+) -> miette::Result<()> {
+    let group = match conversation {
+        Conversation::Group(group) => group,
+        Conversation::Chat(chat) => {
+            println!(
+                "{}: {} messages in total, estimates aren't available for direct messages",
+                conversation.name(),
+                chat.messages_count
+            );
+            return Ok(());
+        }
+    };
+
+    let page = client.get_latest_messages(&group.id).await?;
+    let estimate = estimate::estimate(&page, group.created_at, start, end, types);
+    let about = if estimate.exact { "" } else { "about " };
+    println!(
+        "{}: {} messages in total, {about}{} in the date range with {about}{} attachments",
+        group.name, estimate.total_messages, estimate.messages, estimate.attachments
+    );
+    Ok(())
+}
+
+/// Warn about a date range which can't be downloaded in full, as when its end is
+/// after `now`, or it starts before a conversation did. With several conversations,
+/// only those which don't overlap the range at all are reported.
+fn check_date_range(
+    conversations: &[Conversation],
+    start_date: DateTime<Local>,
+    end_date: DateTime<Local>,
+    now: DateTime<Local>,
+) {
+    let format = |date: DateTime<Local>| date.format("%Y-%m-%d %H:%M").to_string();
+    if end_date > now {
+        warn!(
+            "the end date {} is in the future, messages will be downloaded up to now",
+            format(end_date)
+        );
+    }
+    for conversation in conversations {
+        let created_at = conversation.created_at().with_timezone(&Local);
+        if end_date < created_at {
+            warn!(
+                "{} started on {}, after the end date {}, so there is nothing to download",
+                conversation.name(),
+                format(created_at),
+                format(end_date)
+            );
+        } else if start_date < created_at && conversations.len() == 1 {
+            warn!(
+                "{} started on {}, after the start date {}, so messages will be downloaded from then",
+                conversation.name(),
+                format(created_at),
+                format(start_date)
+            );
+        }
+    }
+}
+
+/// Ask whether to go ahead with downloading a date range longer than `max_days`,
+/// which may be a lot more than was meant to be downloaded. Without a terminal to
+/// ask in, the download is refused, unless `--yes` was given.
+fn confirm_date_range(
+    start_date: DateTime<Local>,
+    end_date: DateTime<Local>,
+    max_days: u32,
+) -> miette::Result<bool> {
+    let days = (end_date - start_date).num_days();
+    if days <= i64::from(max_days) {
+        return Ok(true);
+    }
+    if !std::io::stdin().is_terminal() {
+        miette::bail!(
+            "The date range spans {days} days, more than --confirm-days {max_days}. Use --yes to download it anyway."
+        );
+    }
+    Confirm::new()
+        .with_prompt(format!(
+            "The date range spans {days} days, which may be a lot to download. Continue?"
+        ))
+        .default(false)
+        .interact()
+        .into_diagnostic()
+}
+
+/// Check that the download directory `image_dir` can be written to before
+/// downloading anything. A deleted directory is recreated, but only if its parent
+/// still exists, so that a path on a disconnected drive isn't recreated elsewhere.
+fn ensure_image_dir(image_dir: &Path) -> miette::Result<()> {
+    if !fs::exists(image_dir).into_diagnostic()? {
+        let parent_exists = image_dir
+            .parent()
+            .is_some_and(|parent| parent.as_os_str().is_empty() || parent.is_dir());
+        if !parent_exists {
+            miette::bail!(
+                "Your download directory {image_dir:?} can't be reached, is its drive connected? Use --out or the `set-dir` command to choose another one."
+            );
+        }
+        warn!("your download directory {image_dir:?} no longer exists, creating it");
+        fs::create_dir(image_dir)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Unable to create directory {image_dir:?}"))?;
+    }
+    cache::check_writable(image_dir)
+        .wrap_err("Use --out or the `set-dir` command to choose another download directory")
+}
+
+/// Get the groups the user is a member of, fetching them again with `refresh`,
+/// followed by those they have left if `include_former`.
+async fn get_groups(
+    client: &Client,
+    refresh: bool,
+    include_former: bool,
+) -> miette::Result<Vec<Group>> {
+    let mut groups = client.get_all_groups(refresh).await?;
+    if include_former {
+        groups.extend(client.get_former_groups().await?);
+    }
+    Ok(groups)
+}
+
+/// Pick the group to download from: by `group_id` or `group_name` when given,
+/// otherwise by prompting the user.
+fn select_group(
+    groups: Vec<Group>,
+    group_id: Option<String>,
+    group_name: Option<String>,
+) -> miette::Result<Group> {
+    if let Some(group_id) = group_id {
+        return groups
+            .into_iter()
+            .find(|group| group.id == group_id)
+            .ok_or_else(|| miette::miette!("No group found with id #{group_id}."));
+    }
+
+    if let Some(group_name) = group_name {
+        let mut matches = groups
+            .into_iter()
+            .filter(|group| group.name == group_name)
+            .collect::<Vec<_>>();
+
+        return match matches.len() {
+            0 => Err(miette::miette!("No group found named {group_name:?}.")),
+            1 => Ok(matches.remove(0)),
+            _ => Err(miette::miette!(
+                "Multiple groups are named {group_name:?}, use --group-id with one of: {}",
+                matches
+                    .iter()
+                    .map(|group| group.id.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        };
+    }
+
+    let groups_readable_names = groups
+        .iter()
+        .map(|group| format!("{} (group id #{})", group.name, group.id))
+        .collect::<Vec<_>>();
+
+    let group_idx = Select::new()
+        .with_prompt("Select a group to download images from")
+        .items(&groups_readable_names)
+        .default(0)
+        .interact()
+        .into_diagnostic()?;
+
+    Ok(groups
+        .into_iter()
+        .nth(group_idx)
+        .expect("access is checked by Select"))
+}
+
+/// Prompt the user for a YYYY-MM-DD date, optionally followed by a time.
 fn prompt_date(prompt: impl Display, default: DateTime<Local>) -> miette::Result<DateTime<Local>> {
-    let yyyy_mm_dd: String = Input::new()
-        .with_prompt(format!("{prompt} (format YYYY-MM-DD)"))
+    let input: String = Input::new()
+        .with_prompt(format!("{prompt} (format {DATE_ARG_FORMATS})"))
         .validate_with(|input: &String| {
-            if NaiveDate::from_str(input).is_ok() {
-                Ok(())
-            } else {
-                Err("Invalid date format.")
-            }
+            DateArg::from_str(input)?
+                .resolve()
+                .map(|_| ())
+                .ok_or_else(|| format!("`{input}` doesn't exist in your time zone"))
         })
         .default(default.date_naive().to_string())
         .interact()
         .into_diagnostic()?;
 
-    let naive_date = NaiveDate::from_str(&yyyy_mm_dd).into_diagnostic()?;
-    let naive_time = NaiveTime::default();
-    let naive_datetime = NaiveDateTime::new(naive_date, naive_time);
-    naive_datetime
-        .and_local_timezone(Local)
-        .earliest()
+    DateArg::from_str(&input)
+        .map_err(|err| miette::miette!(err))?
+        .resolve()
         .ok_or_else(|| miette::miette!("Invalid date."))
 }
-
-/// Given a date, round to the beginning of the month, offset by `months` amount
-/// of months into the future.
-fn round_month(time: DateTime<Local>, months: i8) -> Option<DateTime<Local>> {
-    let time = if months < 0 {
-        time.checked_sub_months(Months::new(months.unsigned_abs() as u32))
-    } else {
-        time.checked_add_months(Months::new(months as u32))
-    };
-
-    time?
-        .with_day(1)?
-        .with_time(NaiveTime::default())
-        .earliest()
-}
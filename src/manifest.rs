@@ -0,0 +1,71 @@
+use std::{fs::File, io::ErrorKind, path::Path, path::PathBuf};
+
+use miette::IntoDiagnostic;
+use serde::{Deserialize, Serialize};
+
+use crate::model::Timestamp;
+
+/// The filename the manifest of a download run is written to.
+pub const MANIFEST_FILENAME: &str = "manifest.json";
+
+/// What happened to an attachment during a download run.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Downloaded,
+    Skipped,
+    /// the same content was already downloaded to another file, which the entry's path points to
+    Duplicate,
+    /// the file is larger than `--max-size`, so wasn't downloaded
+    TooLarge,
+    /// the url has expired or the file was removed, so there was nothing to download
+    Unavailable,
+    /// the run's `--max-total` was used up before the attachment was reached
+    NotFetched,
+    Failed,
+}
+
+/// A record of one attachment in a download run.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Entry {
+    /// the name of the group (or direct message partner) the message was sent in
+    pub group: String,
+    pub message_id: String,
+    /// when the message was sent, missing from manifests written by older versions
+    #[serde(default)]
+    pub created_at: Option<Timestamp>,
+    /// the sender's nickname
+    pub user: String,
+    pub url: String,
+    /// where the attachment was (or would have been) saved, if known
+    pub path: Option<PathBuf>,
+    pub status: Status,
+    /// how many bytes were written, if the attachment was downloaded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// the extension the URL or content type suggested, if the content turned out
+    /// to be in another format and the file was named by that instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mislabeled_ext: Option<String>,
+    /// why the attachment failed to download
+    pub error: Option<String>,
+}
+
+/// Write the `entries` of a download run to [`MANIFEST_FILENAME`] in `dir`,
+/// replacing the manifest of any previous run.
+pub fn write_manifest(dir: &Path, entries: &[Entry]) -> miette::Result<()> {
+    let file = File::create(dir.join(MANIFEST_FILENAME)).into_diagnostic()?;
+    serde_path_to_error::serialize(entries, &mut serde_json::Serializer::pretty(file))
+        .into_diagnostic()
+}
+
+/// Read the manifest of the last download run into `dir`, if there is one.
+pub fn read_manifest(dir: &Path) -> miette::Result<Vec<Entry>> {
+    let file = match File::open(dir.join(MANIFEST_FILENAME)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).into_diagnostic(),
+    };
+    serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_reader(file))
+        .into_diagnostic()
+}
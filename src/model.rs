@@ -1,5 +1,8 @@
+use std::borrow::Cow;
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use url::Url;
 
 pub type Timestamp = DateTime<Utc>;
 
@@ -14,6 +17,15 @@ pub struct GroupsResponse {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ResponseMeta {
     pub code: i64,
+    /// Error descriptions, present on unsuccessful responses.
+    #[serde(default)]
+    pub errors: Vec<String>,
+}
+
+/// An unsuccessful API response, which carries only metadata
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ErrorResponse {
+    pub meta: ResponseMeta,
 }
 
 /// A group's definition
@@ -64,26 +76,112 @@ impl GroupMessagesPage {
     }
 }
 
-/// A message in a [`Group`]
+/// An API response
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UserResponse {
+    pub meta: ResponseMeta,
+    pub response: User,
+}
+
+/// The user whose API token is in use
+#[derive(Debug, Deserialize, Serialize)]
+pub struct User {
+    pub id: String,
+    pub name: String,
+}
+
+/// An API response
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChatsResponse {
+    pub meta: ResponseMeta,
+    pub response: Vec<Chat>,
+}
+
+/// A direct message conversation with another user
 #[derive(Debug, Deserialize, Serialize)]
+pub struct Chat {
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub created_at: Timestamp,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub updated_at: Timestamp,
+    pub messages_count: i64,
+    pub other_user: ChatUser,
+}
+
+/// The other participant of a [`Chat`]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChatUser {
+    pub id: String,
+    pub name: String,
+    pub avatar_url: Option<String>,
+}
+
+/// An API response
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DirectMessagesResponse {
+    pub meta: ResponseMeta,
+    pub response: DirectMessagesPage,
+}
+
+/// A page of [`Message`] in a [`Chat`]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DirectMessagesPage {
+    pub count: i64,
+    pub direct_messages: Vec<Message>,
+}
+
+impl From<DirectMessagesPage> for GroupMessagesPage {
+    fn from(page: DirectMessagesPage) -> Self {
+        Self {
+            count: page.count,
+            messages: page.direct_messages,
+        }
+    }
+}
+
+/// A message in a [`Group`] or [`Chat`]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Message {
     pub id: String,
     pub source_guid: String,
     #[serde(with = "chrono::serde::ts_seconds")]
     pub created_at: Timestamp,
     pub user_id: String,
-    pub group_id: String,
+    /// only present for messages in a [`Group`]
+    pub group_id: Option<String>,
+    /// only present for messages in a [`Chat`]
+    pub recipient_id: Option<String>,
     pub name: String,
     pub avatar_url: Option<String>,
     pub text: Option<String>,
+    /// only present for messages in a [`Group`]
+    #[serde(default)]
     pub system: bool,
     /// a list of user IDs
     pub favorited_by: Vec<String>,
     pub attachments: Vec<MessageAttachment>,
 }
 
-/// An attachment on a [`Message`]
+/// The response of the file service's `fileData` endpoint, one item per requested file
+pub type FileDataResponse = Vec<FileDataItem>;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FileDataItem {
+    pub file_data: FileData,
+}
+
+/// What the file service knows about a File attachment
 #[derive(Debug, Deserialize, Serialize)]
+pub struct FileData {
+    pub file_name: String,
+    #[serde(default)]
+    pub file_size: Option<u64>,
+    #[serde(default)]
+    pub mime_type: Option<String>,
+}
+
+/// An attachment on a [`Message`]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum MessageAttachment {
     Image {
@@ -96,8 +194,9 @@ pub enum MessageAttachment {
         url: String,
         preview_url: String,
     },
+    /// a file shared in a [`Group`], resolved with [`crate::client::Client::get_file_data`]
     File {
-        url: String,
+        file_id: String,
     },
     Location {
         lat: String,
@@ -116,10 +215,38 @@ pub enum MessageAttachment {
         reply_id: String,
         base_reply_id: String,
     },
+    /// An attachment type we don't know about yet, kept so that new attachment
+    /// types don't prevent the rest of a [`Message`] from being read.
+    #[serde(untagged)]
+    Unknown {
+        r#type: String,
+    },
+}
+
+/// The kinds of [`MessageAttachment`] which carry downloadable media.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum AttachmentKind {
+    /// images, including linked images
+    Image,
+    Video,
+    File,
 }
 
 impl MessageAttachment {
-    pub fn get_download_url_and_ext(&self) -> Option<(&str, &str)> {
+    /// The kind of media this attachment carries, if any.
+    pub fn kind(&self) -> Option<AttachmentKind> {
+        match self {
+            Self::Image { .. } | Self::LinkedImage { .. } => Some(AttachmentKind::Image),
+            Self::Video { .. } => Some(AttachmentKind::Video),
+            Self::File { .. } => Some(AttachmentKind::File),
+            _ => None,
+        }
+    }
+
+    /// Get the URL to download this attachment from, along with a provisional
+    /// file extension guessed from the URL. The final extension should be chosen
+    /// with [`resolve_ext`] once the response's content type is known.
+    pub fn get_download_url_and_ext(&self) -> Option<(&str, Option<&'static str>)> {
         let url = match self {
             Self::Image { url } => url,
             Self::LinkedImage { url } => url,
@@ -128,18 +255,254 @@ impl MessageAttachment {
         }
         .as_str();
 
-        let ext = if url.contains(".jpeg") {
-            "jpeg"
-        } else if url.contains(".jpg") {
-            "jpg"
-        } else if url.contains(".png") {
-            "png"
-        } else if url.ends_with(".mp4") {
-            "mp4"
-        } else {
-            return None;
+        Some((url, ext_hint(url)))
+    }
+}
+
+/// Guess the file extension of the file at `url` from the URL alone. GroupMe's
+/// image service puts the type before a hash, e.g. `…/1024x768.jpeg.5ab1…`.
+pub fn ext_hint(url: &str) -> Option<&'static str> {
+    if url.contains(".jpeg") {
+        Some("jpeg")
+    } else if url.contains(".jpg") {
+        Some("jpg")
+    } else if url.contains(".png") {
+        Some("png")
+    } else if url.contains(".gif") {
+        Some("gif")
+    } else if url.contains(".webp") {
+        Some("webp")
+    } else if url.ends_with(".mp4") {
+        Some("mp4")
+    } else {
+        None
+    }
+}
+
+/// A size variant of an image hosted by GroupMe's image service.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ImageSize {
+    /// the original, full resolution image
+    #[default]
+    Full,
+    /// a large, downscaled image
+    Large,
+    /// a small thumbnail
+    Preview,
+}
+
+impl ImageSize {
+    const SUFFIXES: [&str; 3] = [".large", ".preview", ".avatar"];
+
+    /// Rewrite an image `url` to request this size. URLs which aren't hosted by
+    /// GroupMe's image service are returned unchanged.
+    pub fn apply(self, url: &str) -> Cow<'_, str> {
+        let Ok(parsed) = Url::parse(url) else {
+            return Cow::Borrowed(url);
+        };
+        if parsed.host_str() != Some("i.groupme.com") {
+            return Cow::Borrowed(url);
+        }
+
+        let base = Self::SUFFIXES
+            .iter()
+            .find_map(|suffix| url.strip_suffix(suffix))
+            .unwrap_or(url);
+
+        match self {
+            Self::Full => Cow::Borrowed(base),
+            Self::Large => Cow::Owned(format!("{base}.large")),
+            Self::Preview => Cow::Owned(format!("{base}.preview")),
+        }
+    }
+}
+
+/// Map a `Content-Type` header value to a file extension.
+pub fn ext_from_content_type(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+
+    Some(match mime.as_str() {
+        "image/jpeg" | "image/jpg" => "jpg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/heic" => "heic",
+        "image/heif" => "heif",
+        "video/mp4" => "mp4",
+        "video/quicktime" => "mov",
+        "video/webm" => "webm",
+        _ => return None,
+    })
+}
+
+/// Choose the final file extension for a download, preferring the response's
+/// `content_type` and falling back to the `provisional` extension from the URL.
+/// When both agree on the format, the provisional spelling (e.g. `jpeg`) is kept
+/// so that previously downloaded files keep their names.
+pub fn resolve_ext<'a>(
+    provisional: Option<&'a str>,
+    content_type: Option<&str>,
+) -> Option<&'a str> {
+    let Some(detected) = content_type.and_then(ext_from_content_type) else {
+        return provisional;
+    };
+
+    match provisional {
+        Some("jpeg") if detected == "jpg" => provisional,
+        _ => Some(detected),
+    }
+}
+
+/// Recognize the format of a file from the signature at the start of its `content`,
+/// for the image and video formats GroupMe serves.
+pub fn sniff_ext(content: &[u8]) -> Option<&'static str> {
+    Some(match content {
+        [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => "png",
+        [0xff, 0xd8, 0xff, ..] => "jpg",
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => "gif",
+        [
+            b'R',
+            b'I',
+            b'F',
+            b'F',
+            _,
+            _,
+            _,
+            _,
+            b'W',
+            b'E',
+            b'B',
+            b'P',
+            ..,
+        ] => "webp",
+        [
+            _,
+            _,
+            _,
+            _,
+            b'f',
+            b't',
+            b'y',
+            b'p',
+            b'q',
+            b't',
+            b' ',
+            b' ',
+            ..,
+        ] => "mov",
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => "mp4",
+        _ => return None,
+    })
+}
+
+/// Whether the extensions `a` and `b` name the same format, e.g. `jpg` and `jpeg`.
+pub fn same_format(a: &str, b: &str) -> bool {
+    let normalize = |ext: &str| match ext.to_ascii_lowercase().as_str() {
+        "jpeg" => "jpg".to_string(),
+        // both are MP4 containers, which a signature alone doesn't tell apart
+        "m4v" => "mp4".to_string(),
+        ext => ext.to_string(),
+    };
+    normalize(a) == normalize(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_with_unknown_attachment_type_deserializes() {
+        let message: Message = serde_json::from_str(
+            r#"{
+                "id": "1",
+                "source_guid": "guid",
+                "created_at": 1700000000,
+                "user_id": "2",
+                "group_id": "3",
+                "name": "user",
+                "avatar_url": null,
+                "text": "vote!",
+                "system": false,
+                "favorited_by": [],
+                "attachments": [
+                    {"type": "poll", "poll_id": "4"},
+                    {"type": "image", "url": "https://i.groupme.com/1x1.png.abc"}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            &message.attachments[0],
+            MessageAttachment::Unknown { r#type } if r#type == "poll"
+        ));
+        assert!(message.attachments[0].get_download_url_and_ext().is_none());
+        assert!(matches!(
+            &message.attachments[1],
+            MessageAttachment::Image { .. }
+        ));
+    }
+
+    #[test]
+    fn ext_hint_recognizes_gifs() {
+        assert_eq!(
+            ext_hint("https://i.groupme.com/480x270.gif.5ab1"),
+            Some("gif")
+        );
+        assert_eq!(
+            ext_hint("https://media.giphy.com/media/xT9IgG50Fb7Mi0prBC/giphy.gif"),
+            Some("gif")
+        );
+    }
+
+    #[test]
+    fn ext_hint_recognizes_webp() {
+        assert_eq!(
+            ext_hint("https://i.groupme.com/800x600.webp.5ab1"),
+            Some("webp")
+        );
+    }
+
+    #[test]
+    fn linked_gifs_are_downloaded() {
+        let attachment = MessageAttachment::LinkedImage {
+            url: "https://media.giphy.com/media/xT9IgG50Fb7Mi0prBC/giphy.gif".to_string(),
         };
+        assert_eq!(
+            attachment
+                .get_download_url_and_ext()
+                .and_then(|(_, ext)| ext),
+            Some("gif")
+        );
+    }
+
+    #[test]
+    fn resolve_ext_from_gif_and_webp_content_types() {
+        assert_eq!(resolve_ext(None, Some("image/gif")), Some("gif"));
+        assert_eq!(
+            resolve_ext(None, Some("image/webp; charset=binary")),
+            Some("webp")
+        );
+        // the content type wins over a misleading URL
+        assert_eq!(resolve_ext(Some("jpeg"), Some("image/webp")), Some("webp"));
+    }
+
+    #[test]
+    fn sniff_ext_recognizes_signatures() {
+        assert_eq!(sniff_ext(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some("png"));
+        assert_eq!(sniff_ext(b"\xff\xd8\xff\xe0\0\x10JFIF"), Some("jpg"));
+        assert_eq!(sniff_ext(b"GIF89a\x01\0"), Some("gif"));
+        assert_eq!(sniff_ext(b"RIFF\x24\0\0\0WEBPVP8 "), Some("webp"));
+        assert_eq!(sniff_ext(b"\0\0\0\x20ftypisom\0\0\x02\0"), Some("mp4"));
+        assert_eq!(sniff_ext(b"\0\0\0\x14ftypqt  \0\0\0\0"), Some("mov"));
+        assert_eq!(sniff_ext(b"<html>"), None);
+        assert_eq!(sniff_ext(b""), None);
+    }
 
-        Some((url, ext))
+    #[test]
+    fn same_format_ignores_spelling() {
+        assert!(same_format("jpeg", "jpg"));
+        assert!(same_format("PNG", "png"));
+        assert!(!same_format("png", "jpg"));
     }
 }
@@ -0,0 +1,196 @@
+use std::{
+    cell::Cell,
+    io::Write,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use indicatif::{
+    HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle,
+};
+
+use serde::Serialize;
+use tracing::warn;
+
+use crate::events::{self, Event};
+use crate::manifest::{Entry, Status};
+
+/// Create the display all progress bars are drawn to, on stderr, or a hidden one when `quiet`.
+pub fn multi_progress(quiet: bool) -> MultiProgress {
+    if quiet {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    } else {
+        MultiProgress::new()
+    }
+}
+
+/// A writer to stderr which hides the progress bars while writing, so that log
+/// lines don't get mixed up with them.
+pub struct LogWriter(pub MultiProgress);
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.suspend(|| std::io::stderr().write(buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
+
+/// Overall progress of a download run, shown as a spinner with running counts.
+/// The counts are shared by every conversation downloaded in the run.
+pub struct Progress {
+    multi: MultiProgress,
+    bar: ProgressBar,
+    started_at: Instant,
+    scanned: Cell<usize>,
+    downloaded: Cell<usize>,
+    skipped: Cell<usize>,
+    failed: Cell<usize>,
+    /// bytes written by downloads
+    bytes: Cell<u64>,
+    /// whether each attachment is also reported as an [`Event`] on stdout
+    events: bool,
+}
+
+/// The counts of a download run, as reported by [`Event::Summary`].
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Totals {
+    pub scanned: usize,
+    pub downloaded: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub bytes: u64,
+    pub elapsed_secs: f64,
+}
+
+impl Progress {
+    /// Start showing progress on `multi`. With `events`, each attachment is also
+    /// reported as an [`Event`] on stdout.
+    pub fn new(multi: &MultiProgress, events: bool) -> Self {
+        let bar = multi.add(ProgressBar::new_spinner());
+        bar.enable_steady_tick(Duration::from_millis(120));
+        let progress = Self {
+            multi: multi.clone(),
+            bar,
+            started_at: Instant::now(),
+            scanned: Cell::new(0),
+            downloaded: Cell::new(0),
+            skipped: Cell::new(0),
+            failed: Cell::new(0),
+            bytes: Cell::new(0),
+            events,
+        };
+        progress.redraw();
+        progress
+    }
+
+    /// Count a message read from the message stream.
+    pub fn message_scanned(&self) {
+        increment(&self.scanned, 1);
+        self.redraw();
+    }
+
+    /// Count an attachment which is done, one way or another.
+    pub fn record(&self, entry: &Entry) {
+        increment(&self.bytes, entry.size.unwrap_or_default());
+        match entry.status {
+            Status::Downloaded => increment(&self.downloaded, 1),
+            Status::Skipped
+            | Status::Duplicate
+            | Status::TooLarge
+            | Status::Unavailable
+            | Status::NotFetched => increment(&self.skipped, 1),
+            Status::Failed => increment(&self.failed, 1),
+        }
+        self.redraw();
+        if self.events
+            && let Err(err) = events::emit(&Event::Attachment(entry))
+        {
+            warn!("unable to write event: {err}");
+        }
+    }
+
+    /// How many attachments have been downloaded.
+    pub fn downloaded(&self) -> usize {
+        self.downloaded.get()
+    }
+
+    /// How many bytes downloads have written.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.get()
+    }
+
+    /// How many attachments failed to download.
+    pub fn failed(&self) -> usize {
+        self.failed.get()
+    }
+
+    /// Show a bar for a file being downloaded to `filepath`, `len` bytes long if known.
+    /// The bar should be removed with [`ProgressBar::finish_and_clear`] once done.
+    pub fn download_bar(multi: &MultiProgress, filepath: &Path, len: Option<u64>) -> ProgressBar {
+        let bar = match len {
+            Some(len) => ProgressBar::new(len).with_style(
+                ProgressStyle::with_template("  {wide_msg} {bytes}/{total_bytes} [{bar:20}]")
+                    .expect("valid progress template")
+                    .progress_chars("=> "),
+            ),
+            None => ProgressBar::new_spinner().with_style(
+                ProgressStyle::with_template("  {wide_msg} {bytes}")
+                    .expect("valid progress template"),
+            ),
+        };
+        let filename = filepath.file_name().unwrap_or_default().to_string_lossy();
+        multi.add(bar.with_message(filename.into_owned()))
+    }
+
+    /// The display this progress is drawn to, for adding download bars.
+    pub fn multi(&self) -> &MultiProgress {
+        &self.multi
+    }
+
+    /// Remove the spinner once the run is over.
+    pub fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+
+    /// A one line summary of the run so far.
+    pub fn summary(&self) -> String {
+        format!(
+            "Scanned {} messages: {} files downloaded ({}), {} skipped, {} failed in {}.",
+            self.scanned.get(),
+            self.downloaded.get(),
+            HumanBytes(self.bytes.get()),
+            self.skipped.get(),
+            self.failed.get(),
+            HumanDuration(self.started_at.elapsed())
+        )
+    }
+
+    /// The counts of the run so far.
+    pub fn totals(&self) -> Totals {
+        Totals {
+            scanned: self.scanned.get(),
+            downloaded: self.downloaded.get(),
+            skipped: self.skipped.get(),
+            failed: self.failed.get(),
+            bytes: self.bytes.get(),
+            elapsed_secs: self.started_at.elapsed().as_secs_f64(),
+        }
+    }
+
+    fn redraw(&self) {
+        self.bar.set_message(format!(
+            "{} messages scanned, {} downloaded, {} skipped, {} failed",
+            self.scanned.get(),
+            self.downloaded.get(),
+            self.skipped.get(),
+            self.failed.get()
+        ));
+    }
+}
+
+fn increment<T: Copy + std::ops::Add<Output = T>>(count: &Cell<T>, by: T) {
+    count.set(count.get() + by);
+}
@@ -0,0 +1,76 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// How far a delay may stray from its base, as a fraction of it, so that
+/// requests from several groups at once don't fall into step.
+const JITTER: f64 = 0.25;
+
+/// Spaces out requests, handing out one turn per `interval` (give or take the
+/// [`JITTER`]) to whoever asks, across all clones of the limiter.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    interval: Duration,
+    /// when the next turn is due, `None` until the first is taken
+    next_turn: Arc<Mutex<Option<Instant>>>,
+}
+
+impl RateLimiter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_turn: Arc::default(),
+        }
+    }
+
+    /// Wait for our turn to make a request. Turns are handed out in the order
+    /// they are asked for, without anyone holding the others up while waiting.
+    pub async fn acquire(&self) {
+        let turn = {
+            let mut next_turn = self.next_turn.lock().expect("not poisoned");
+            let now = Instant::now();
+            let turn = next_turn.map_or(now, |next_turn| next_turn.max(now));
+            *next_turn = Some(turn + jittered(self.interval));
+            turn
+        };
+        tokio::time::sleep_until(turn).await;
+    }
+}
+
+/// `delay`, give or take up to [`JITTER`] of it at random.
+pub fn jittered(delay: Duration) -> Duration {
+    delay.mul_f64(1.0 + JITTER * (2.0 * fastrand::f64() - 1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jittered_delays_stay_near_the_base() {
+        let base = Duration::from_millis(1_000);
+        for _ in 0..100 {
+            let delay = jittered(base);
+            assert!(delay >= base.mul_f64(1.0 - JITTER), "{delay:?}");
+            assert!(delay <= base.mul_f64(1.0 + JITTER), "{delay:?}");
+        }
+        assert_eq!(jittered(Duration::ZERO), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn turns_are_spaced_out_across_clones() {
+        let interval = Duration::from_millis(20);
+        let limiter = RateLimiter::new(interval);
+        let clone = limiter.clone();
+        let started_at = Instant::now();
+
+        limiter.acquire().await;
+        clone.acquire().await;
+        limiter.acquire().await;
+
+        assert!(started_at.elapsed() >= interval.mul_f64(2.0 * (1.0 - JITTER)));
+    }
+}
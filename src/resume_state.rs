@@ -0,0 +1,43 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{cache::Cache, error::Result, model::Timestamp};
+
+/// How far back through a date range a download from a conversation got,
+/// remembered so that `--resume` can carry on from there after an interruption.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ResumeState {
+    /// the oldest message whose attachments were all downloaded, which the
+    /// messages still to download were sent before
+    pub before_id: String,
+    /// the date range being downloaded
+    pub newest: Timestamp,
+    pub oldest: Timestamp,
+}
+
+const FILENAME_PREFIX: &str = "resume_";
+
+/// Whether `filename` is a cache file holding a [`ResumeState`].
+pub fn is_resume_state_file(filename: &str) -> bool {
+    filename.starts_with(FILENAME_PREFIX)
+}
+
+/// The cache file a conversation's [`ResumeState`] is kept in, `key` being the
+/// group id, or `dm_<other user id>` for direct messages.
+fn filename(key: &str) -> String {
+    format!("{FILENAME_PREFIX}{key}.json")
+}
+
+/// Get the [`ResumeState`] of the conversation identified by `key`, if a download from it was interrupted.
+pub fn read(cache: &Cache, key: &str) -> Result<Option<ResumeState>> {
+    cache.read_cache_item(filename(key))
+}
+
+/// Remember `state` as how far the download from the conversation identified by `key` got.
+pub fn write(cache: &Cache, key: &str, state: &ResumeState) -> Result<()> {
+    cache.write_cache_item(filename(key), state)
+}
+
+/// Forget how far the download from the conversation identified by `key` got, once it is complete.
+pub fn remove(cache: &Cache, key: &str) -> Result<()> {
+    cache.remove_cache_item(filename(key))
+}
@@ -0,0 +1,173 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use reqwest::{
+    StatusCode,
+    header::{HeaderMap, RETRY_AFTER},
+};
+use tracing::warn;
+
+use crate::{
+    error::{Error, Result},
+    rate_limit,
+};
+
+/// How many times a failed request is retried, unless configured otherwise.
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+
+/// The delay before the first retry, doubled for every retry after that.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The longest a 429 response's `Retry-After` is waited for. Asked to wait any
+/// longer, we give up rather than stall the run.
+pub const MAX_RETRY_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// Why an attempt failed, and whether it is worth trying again.
+pub enum Failure {
+    /// A failure which may resolve itself, e.g. a network error or a 5xx response.
+    Transient(Error),
+    /// A 429 response; try again once `retry_after` has passed, if the server said when.
+    RateLimited {
+        error: Error,
+        retry_after: Option<Duration>,
+    },
+    /// A failure which retrying won't fix.
+    Permanent(Error),
+}
+
+impl Failure {
+    /// Classify a failed request: anything but an error status is a network
+    /// problem worth retrying.
+    pub fn from_reqwest(err: reqwest::Error) -> Self {
+        match err.status() {
+            Some(status) => Self::from_status(status, &HeaderMap::new(), err.into()),
+            None => Self::Transient(err.into()),
+        }
+    }
+
+    /// Classify an unsuccessful response by its `status` and `headers`.
+    pub fn from_status(status: StatusCode, headers: &HeaderMap, error: Error) -> Self {
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            Self::RateLimited {
+                error,
+                retry_after: retry_after(headers),
+            }
+        } else if status.is_server_error() {
+            Self::Transient(error)
+        } else {
+            Self::Permanent(error)
+        }
+    }
+}
+
+/// Read how long to wait before retrying from a `Retry-After` header, which is
+/// either a number of seconds or an HTTP date. GroupMe doesn't document any
+/// `X-RateLimit-*` headers, so this is the only hint we get.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.to_utc() - Utc::now()).to_std().ok()
+}
+
+impl<E: Into<Error>> From<E> for Failure {
+    fn from(error: E) -> Self {
+        Self::Permanent(error.into())
+    }
+}
+
+/// Run `attempt`, retrying transient failures up to `max_retries` times with
+/// exponential backoff. Rate limited attempts are retried once the server's
+/// `Retry-After` has passed, unless that is longer than [`MAX_RETRY_AFTER`].
+pub async fn with_retries<T, F>(max_retries: u32, mut attempt: impl FnMut() -> F) -> Result<T>
+where
+    F: Future<Output = Result<T, Failure>>,
+{
+    let mut backoff = INITIAL_BACKOFF;
+    let mut retries = 0;
+
+    loop {
+        match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(Failure::Transient(error)) if retries < max_retries => {
+                drop(error);
+                tokio::time::sleep(rate_limit::jittered(backoff)).await;
+                backoff *= 2;
+                retries += 1;
+            }
+            Err(Failure::RateLimited {
+                error,
+                retry_after: Some(retry_after),
+            }) if retry_after > MAX_RETRY_AFTER => {
+                warn!(
+                    "GroupMe asked to wait {}s before retrying, longer than the {}s we wait at most",
+                    retry_after.as_secs(),
+                    MAX_RETRY_AFTER.as_secs()
+                );
+                return Err(error);
+            }
+            Err(Failure::RateLimited { retry_after, .. }) if retries < max_retries => {
+                tokio::time::sleep(retry_after.unwrap_or_else(|| rate_limit::jittered(backoff)))
+                    .await;
+                backoff *= 2;
+                retries += 1;
+            }
+            Err(Failure::Transient(error) | Failure::RateLimited { error, .. }) => {
+                return Err(Error::GaveUp {
+                    retries,
+                    source: Box::new(error),
+                });
+            }
+            Err(Failure::Permanent(error)) => return Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        [(RETRY_AFTER, HeaderValue::from_str(retry_after).unwrap())]
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn retry_after_is_read_as_seconds_or_a_date() {
+        assert_eq!(retry_after(&headers("120")), Some(Duration::from_secs(120)));
+        let in_an_hour = (Utc::now() + chrono::Duration::hours(1)).to_rfc2822();
+        let wait = retry_after(&headers(&in_an_hour)).unwrap();
+        assert!(wait > Duration::from_secs(3590) && wait <= Duration::from_secs(3600));
+        assert_eq!(retry_after(&headers("soon")), None);
+    }
+
+    #[tokio::test]
+    async fn waiting_longer_than_the_most_retry_after_gives_up() {
+        let attempts = Cell::new(0);
+
+        let err = with_retries::<(), _>(3, || {
+            attempts.set(attempts.get() + 1);
+            async {
+                Err(Failure::from_status(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    &headers(&(MAX_RETRY_AFTER.as_secs() + 1).to_string()),
+                    Error::RateLimited { details: None },
+                ))
+            }
+        })
+        .await
+        .unwrap_err();
+
+        assert!(matches!(err, Error::RateLimited { .. }), "{err:?}");
+        assert_eq!(attempts.get(), 1);
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use miette::IntoDiagnostic;
+use serde::Serialize;
+
+use crate::model::{Message, Timestamp};
+
+/// What is known of the message an attachment was sent with, written next to
+/// the downloaded file by [`write_sidecar`] so that photo managers can search by it.
+#[derive(Clone, Debug, Serialize)]
+pub struct Sidecar {
+    /// the name of the group (or direct message partner) the message was sent in
+    pub group: String,
+    pub message_id: String,
+    /// the sender's nickname
+    pub user: String,
+    pub created_at: Timestamp,
+    pub text: Option<String>,
+    /// how many members liked the message
+    pub likes: usize,
+}
+
+impl Sidecar {
+    /// The sidecar of the attachments of `message`, sent by `user` in `group`.
+    pub fn new(message: &Message, group: &str, user: &str) -> Self {
+        Self {
+            group: group.to_string(),
+            message_id: message.id.clone(),
+            user: user.to_string(),
+            created_at: message.created_at,
+            text: message.text.clone(),
+            likes: message.favorited_by.len(),
+        }
+    }
+}
+
+/// Where the sidecar of the file at `path` is written: next to it, with `.json`
+/// appended to its name, so that it isn't mistaken for another attachment.
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut sidecar_path = path.as_os_str().to_owned();
+    sidecar_path.push(".json");
+    sidecar_path.into()
+}
+
+/// Write `sidecar` next to the file at `path`. An existing sidecar is only
+/// replaced if `overwrite` is set.
+pub fn write_sidecar(path: &Path, sidecar: &Sidecar, overwrite: bool) -> miette::Result<()> {
+    let sidecar_path = sidecar_path(path);
+    if !overwrite && fs::exists(&sidecar_path).into_diagnostic()? {
+        return Ok(());
+    }
+
+    let file = File::create(&sidecar_path).into_diagnostic()?;
+    serde_json::to_writer_pretty(&file, sidecar).into_diagnostic()?;
+    // dated like the file it describes
+    file.set_modified(sidecar.created_at.into())
+        .into_diagnostic()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    fn sidecar(text: &str) -> Sidecar {
+        Sidecar {
+            group: "friends".to_string(),
+            message_id: "1".to_string(),
+            user: "alice".to_string(),
+            created_at: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            text: Some(text.to_string()),
+            likes: 2,
+        }
+    }
+
+    #[test]
+    fn sidecar_path_appends_to_the_file_name() {
+        assert_eq!(
+            sidecar_path(Path::new("group/2024-01-01_alice_0.jpg")),
+            Path::new("group/2024-01-01_alice_0.jpg.json")
+        );
+    }
+
+    #[test]
+    fn write_sidecar_writes_the_fields_dated_like_the_message() {
+        let dir = std::env::temp_dir().join(format!("sidecar-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("photo.jpg");
+        let sidecar_path = sidecar_path(&path);
+
+        write_sidecar(&path, &sidecar("hi"), false).unwrap();
+        let written = fs::read_to_string(&sidecar_path).unwrap();
+        let modified = fs::metadata(&sidecar_path).unwrap().modified().unwrap();
+        // an existing sidecar is kept, unless overwriting
+        write_sidecar(&path, &sidecar("edited"), false).unwrap();
+        let kept = fs::read_to_string(&sidecar_path).unwrap();
+        write_sidecar(&path, &sidecar("edited"), true).unwrap();
+        let overwritten = fs::read_to_string(&sidecar_path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&written).unwrap(),
+            serde_json::json!({
+                "group": "friends",
+                "message_id": "1",
+                "user": "alice",
+                "created_at": "2023-11-14T22:13:20Z",
+                "text": "hi",
+                "likes": 2,
+            })
+        );
+        assert_eq!(
+            modified,
+            std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000)
+        );
+        assert_eq!(kept, written);
+        assert!(overwritten.contains("edited"));
+    }
+}
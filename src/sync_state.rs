@@ -0,0 +1,34 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{cache::Cache, error::Result, model::Timestamp};
+
+/// The newest message downloaded from a conversation, remembered so that
+/// `--since-last` only has to fetch what came after it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SyncState {
+    pub message_id: String,
+    pub created_at: Timestamp,
+}
+
+const FILENAME_PREFIX: &str = "last_sync_";
+
+/// Whether `filename` is a cache file holding a [`SyncState`].
+pub fn is_sync_state_file(filename: &str) -> bool {
+    filename.starts_with(FILENAME_PREFIX)
+}
+
+/// The cache file a conversation's [`SyncState`] is kept in, `key` being the
+/// group id, or `dm_<other user id>` for direct messages.
+fn filename(key: &str) -> String {
+    format!("{FILENAME_PREFIX}{key}.json")
+}
+
+/// Get the [`SyncState`] of the conversation identified by `key`, if it was downloaded before.
+pub fn read(cache: &Cache, key: &str) -> Result<Option<SyncState>> {
+    cache.read_cache_item(filename(key))
+}
+
+/// Remember `state` as the newest message downloaded from the conversation identified by `key`.
+pub fn write(cache: &Cache, key: &str, state: &SyncState) -> Result<()> {
+    cache.write_cache_item(filename(key), state)
+}
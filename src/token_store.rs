@@ -0,0 +1,51 @@
+//! Storage for the API token in the OS keyring, when the `keyring` feature is enabled.
+
+use crate::error::{Error, Result};
+
+#[cfg(feature = "keyring")]
+const SERVICE: &str = "groupme_downloader";
+
+#[cfg(feature = "keyring")]
+const USER: &str = "api_token";
+
+/// The keyring entry the API token of `profile` (the default one if `None`) is kept in.
+#[cfg(feature = "keyring")]
+fn entry(profile: Option<&str>) -> keyring::Result<keyring::Entry> {
+    match profile {
+        Some(profile) => keyring::Entry::new(SERVICE, &format!("{USER}.{profile}")),
+        None => keyring::Entry::new(SERVICE, USER),
+    }
+}
+
+/// Store the `api_token` of `profile` in the OS keyring, returning whether that succeeded.
+#[cfg(feature = "keyring")]
+pub fn store_token(profile: Option<&str>, api_token: &str) -> bool {
+    entry(profile)
+        .and_then(|entry| entry.set_password(api_token))
+        .inspect_err(|err| tracing::warn!("unable to store the API token in the OS keyring: {err}"))
+        .is_ok()
+}
+
+/// Store the `api_token` of `profile` in the OS keyring, returning whether that succeeded.
+#[cfg(not(feature = "keyring"))]
+pub fn store_token(_profile: Option<&str>, _api_token: &str) -> bool {
+    false
+}
+
+/// Read the API token of `profile` from the OS keyring.
+#[cfg(feature = "keyring")]
+pub fn load_token(profile: Option<&str>) -> Result<String> {
+    entry(profile)
+        .and_then(|entry| entry.get_password())
+        .map_err(|err| Error::Keyring {
+            source: Box::new(err),
+        })
+}
+
+/// Read the API token of `profile` from the OS keyring.
+#[cfg(not(feature = "keyring"))]
+pub fn load_token(_profile: Option<&str>) -> Result<String> {
+    Err(Error::Keyring {
+        source: "This build does not support reading the API token from the OS keyring.".into(),
+    })
+}
@@ -0,0 +1,58 @@
+use bytes::Bytes;
+use reqwest::{StatusCode, header::HeaderMap};
+use url::Url;
+
+use crate::retry::Failure;
+
+/// A response to a GET request, read in full.
+#[derive(Clone, Debug)]
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+/// Makes the GroupMe API requests of a [`Client`](crate::client::Client), so that
+/// tests can stand in for the live API.
+pub trait HttpTransport: Clone + Send + Sync + 'static {
+    /// Make a GET request to `url`. Unsuccessful statuses are not errors, only
+    /// failing to get a response at all is.
+    fn get(&self, url: Url) -> impl Future<Output = Result<HttpResponse, Failure>> + Send;
+}
+
+/// An [`HttpTransport`] making real requests with [`reqwest`].
+#[derive(Clone)]
+pub struct ReqwestTransport {
+    http: reqwest::Client,
+}
+
+impl ReqwestTransport {
+    /// Instantiate a [`ReqwestTransport`] sending requests through `http`.
+    pub fn new(http: reqwest::Client) -> Self {
+        Self { http }
+    }
+}
+
+impl HttpTransport for ReqwestTransport {
+    async fn get(&self, url: Url) -> Result<HttpResponse, Failure> {
+        let response = self
+            .http
+            .get(url)
+            .send()
+            .await
+            // the URL includes the API token
+            .map_err(|err| Failure::from_reqwest(err.without_url()))?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response
+            .bytes()
+            .await
+            .map_err(|err| Failure::from_reqwest(err.without_url()))?;
+
+        Ok(HttpResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+}
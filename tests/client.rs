@@ -0,0 +1,148 @@
+//! The [`Client`](groupme_download_rust::client::Client) against a mock GroupMe
+//! API, so that real requests are built and sent.
+
+mod common;
+
+use chrono::{TimeZone, Utc};
+use futures_util::StreamExt;
+use groupme_download_rust::{client::DEFAULT_USER_AGENT, error::Error};
+use serde_json::json;
+
+use common::{MockResponse, MockServer, TempDir, group_json, message_json, messages_page};
+
+#[tokio::test]
+async fn get_all_groups_reads_every_page() {
+    let server = MockServer::start();
+    let groups = |ids: std::ops::RangeInclusive<usize>| {
+        ids.map(|id| group_json(&id.to_string()))
+            .collect::<Vec<_>>()
+    };
+    server.mock(
+        "/v3/groups",
+        &[("page", "1")],
+        MockResponse::api(json!(groups(1..=500))),
+    );
+    server.mock(
+        "/v3/groups",
+        &[("page", "2")],
+        MockResponse::api(json!(groups(501..=502))),
+    );
+    let dir = TempDir::new("groups");
+    let client = common::client(&dir, &common::config(&server, &dir));
+
+    let groups = client.get_all_groups(true).await.unwrap();
+
+    assert_eq!(groups.len(), 502);
+    let requests = server.requests_to("/v3/groups");
+    assert_eq!(
+        requests
+            .iter()
+            .map(|request| request.query("page"))
+            .collect::<Vec<_>>(),
+        [Some("1"), Some("2")]
+    );
+    for request in &requests {
+        assert_eq!(request.query("per_page"), Some("500"));
+        assert_eq!(request.query("token"), Some("token"));
+        assert_eq!(request.header("user-agent"), Some(DEFAULT_USER_AGENT));
+    }
+}
+
+#[tokio::test]
+async fn get_messages_stays_within_the_date_range() {
+    let server = MockServer::start();
+    let attachments = Vec::new;
+    server.mock(
+        "/v3/groups/1/messages",
+        &[("before_id", "3")],
+        MockResponse::api(messages_page(vec![
+            message_json("2", 200, attachments()),
+            message_json("1", 100, attachments()),
+        ])),
+    );
+    server.mock(
+        "/v3/groups/1/messages",
+        &[],
+        MockResponse::api(messages_page(vec![
+            message_json("4", 400, attachments()),
+            message_json("3", 300, attachments()),
+        ])),
+    );
+    let dir = TempDir::new("messages");
+    let client = common::client(&dir, &common::config(&server, &dir)).with_messages_per_page(2);
+
+    let messages = client
+        .get_messages(
+            Utc.timestamp_opt(350, 0).unwrap(),
+            Utc.timestamp_opt(150, 0).unwrap(),
+            "1".to_string(),
+            None,
+        )
+        .await
+        .unwrap()
+        .map(|message| message.unwrap().id)
+        .collect::<Vec<_>>()
+        .await;
+
+    assert_eq!(messages, ["3", "2"]);
+    // the second page reaches past the start of the range, so is the last
+    let requests = server.requests_to("/v3/groups/1/messages");
+    assert_eq!(
+        requests
+            .iter()
+            .map(|request| request.query("before_id"))
+            .collect::<Vec<_>>(),
+        [None, Some("3")]
+    );
+    assert!(
+        requests
+            .iter()
+            .all(|request| request.query("limit") == Some("2"))
+    );
+}
+
+#[tokio::test]
+async fn a_rate_limited_request_is_retried() {
+    let server = MockServer::start();
+    server.mock_times(
+        "/v3/users/me",
+        &[],
+        MockResponse::new(429, "").with_header("Retry-After", "0"),
+        1,
+    );
+    server.mock(
+        "/v3/users/me",
+        &[],
+        MockResponse::api(json!({"id": "1", "name": "alice"})),
+    );
+    let dir = TempDir::new("retry");
+    let client = common::client(&dir, &common::config(&server, &dir)).with_max_retries(1);
+
+    let me = client.get_me().await.unwrap();
+
+    assert_eq!(me.name, "alice");
+    assert_eq!(server.requests_to("/v3/users/me").len(), 2);
+}
+
+#[tokio::test]
+async fn a_rate_limited_request_gives_up_after_the_retries() {
+    let server = MockServer::start();
+    server.mock(
+        "/v3/users/me",
+        &[],
+        MockResponse::new(429, "").with_header("Retry-After", "0"),
+    );
+    let dir = TempDir::new("give-up");
+    let client = common::client(&dir, &common::config(&server, &dir)).with_max_retries(2);
+
+    let err = client.get_me().await.unwrap_err();
+
+    assert!(
+        matches!(
+            &err,
+            Error::GaveUp { retries: 2, source } if matches!(**source, Error::RateLimited { .. })
+        ),
+        "{err:?}"
+    );
+    assert_eq!(server.requests_to("/v3/users/me").len(), 3);
+}
@@ -0,0 +1,343 @@
+//! A stand-in for the GroupMe API, and the servers attachments are downloaded
+//! from, which the integration tests make real HTTP requests to.
+
+// each test binary uses its own share of the helpers
+#![allow(dead_code)]
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use groupme_download_rust::{cache::Cache, client::Client, config::Config};
+use serde_json::{Value, json};
+use url::Url;
+
+/// A response of a [`MockServer`].
+#[derive(Clone, Debug)]
+pub struct MockResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    /// the `Content-Length` sent, if it isn't the length of the body
+    content_length: Option<usize>,
+}
+
+impl MockResponse {
+    pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.into(),
+            content_length: None,
+        }
+    }
+
+    /// A successful API response, wrapping `response` as GroupMe does.
+    pub fn api(response: Value) -> Self {
+        Self::new(
+            200,
+            json!({"meta": {"code": 200}, "response": response}).to_string(),
+        )
+        .with_header("Content-Type", "application/json")
+    }
+
+    /// A successful response with an image as its body.
+    pub fn image(body: &[u8]) -> Self {
+        Self::new(200, body).with_header("Content-Type", "image/jpeg")
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Claim a body of `content_length` bytes but send less, as a dropped connection does.
+    pub fn cut_short(self, content_length: usize) -> Self {
+        Self {
+            content_length: Some(content_length),
+            ..self
+        }
+    }
+}
+
+/// A request received by a [`MockServer`].
+#[derive(Clone, Debug)]
+pub struct MockRequest {
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    /// with lowercase names
+    pub headers: Vec<(String, String)>,
+}
+
+impl MockRequest {
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+struct Mock {
+    path: String,
+    query: Vec<(String, String)>,
+    response: MockResponse,
+    /// how many more requests it answers, if that is limited
+    remaining: Option<usize>,
+}
+
+#[derive(Default)]
+struct State {
+    mocks: Vec<Mock>,
+    requests: Vec<MockRequest>,
+}
+
+/// An HTTP server on a local port, answering requests with the responses mocked
+/// for them, and `404 Not Found` otherwise. It runs until the test ends.
+#[derive(Clone)]
+pub struct MockServer {
+    uri: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockServer {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = Self {
+            uri: format!("http://{}", listener.local_addr().unwrap()),
+            state: Arc::default(),
+        };
+        let handler = server.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let handler = handler.clone();
+                thread::spawn(move || handler.handle(stream));
+            }
+        });
+        server
+    }
+
+    /// The base URL of the server, e.g. `http://127.0.0.1:1234`.
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    /// Answer requests to `path` whose query includes the `query` pairs with
+    /// `response`. Of the mocks matching a request, the first added answers it.
+    pub fn mock(&self, path: &str, query: &[(&str, &str)], response: MockResponse) {
+        self.add(path, query, response, None);
+    }
+
+    /// Like [`Self::mock`], but only answer the first `times` matching requests.
+    pub fn mock_times(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+        response: MockResponse,
+        times: usize,
+    ) {
+        self.add(path, query, response, Some(times));
+    }
+
+    fn add(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+        response: MockResponse,
+        remaining: Option<usize>,
+    ) {
+        self.state.lock().unwrap().mocks.push(Mock {
+            path: path.to_string(),
+            query: query
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            response,
+            remaining,
+        });
+    }
+
+    /// The requests received to `path`, in the order they were.
+    pub fn requests_to(&self, path: &str) -> Vec<MockRequest> {
+        self.state
+            .lock()
+            .unwrap()
+            .requests
+            .iter()
+            .filter(|request| request.path == path)
+            .cloned()
+            .collect()
+    }
+
+    fn handle(&self, stream: TcpStream) {
+        let Some(request) = read_request(&stream) else {
+            return;
+        };
+        let response = {
+            let mut state = self.state.lock().unwrap();
+            state.requests.push(request.clone());
+            state
+                .mocks
+                .iter_mut()
+                .find(|mock| {
+                    mock.path == request.path
+                        && mock.remaining != Some(0)
+                        && mock
+                            .query
+                            .iter()
+                            .all(|(key, value)| request.query(key) == Some(value))
+                })
+                .map(|mock| {
+                    if let Some(remaining) = &mut mock.remaining {
+                        *remaining -= 1;
+                    }
+                    mock.response.clone()
+                })
+                .unwrap_or_else(|| MockResponse::new(404, ""))
+        };
+        let _ = write_response(stream, &response);
+    }
+}
+
+/// Read the head of a request, which is all the server looks at.
+fn read_request(stream: &TcpStream) -> Option<MockRequest> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).ok()?;
+    let target = request_line.split_whitespace().nth(1)?;
+    let url = Url::parse(&format!("http://localhost{target}")).ok()?;
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).ok()? == 0 {
+            break;
+        }
+        let Some((name, value)) = line.trim_end().split_once(':') else {
+            break;
+        };
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+    }
+
+    Some(MockRequest {
+        path: url.path().to_string(),
+        query: url.query_pairs().into_owned().collect(),
+        headers,
+    })
+}
+
+fn write_response(mut stream: TcpStream, response: &MockResponse) -> std::io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.content_length.unwrap_or(response.body.len())
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
+
+/// A directory for a test's files, removed when dropped.
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "groupme-test-{name}-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A config making API requests to `server`, and downloading into `dir`'s `images`.
+pub fn config(server: &MockServer, dir: &TempDir) -> Config {
+    Config {
+        api_base_url: Some(format!("{}/v3", server.uri())),
+        ..Config::new("token".to_string(), dir.path().join("images"))
+    }
+}
+
+/// A client of `config`, caching in `dir`, which doesn't wait between requests
+/// nor retry them.
+pub fn client(dir: &TempDir, config: &Config) -> Client {
+    let cache = Cache::in_dir(dir.path());
+    std::fs::create_dir_all(cache.cache_dir()).unwrap();
+    Client::new(cache, config.clone(), Duration::from_secs(5), None)
+        .unwrap()
+        .with_page_delay(Duration::ZERO)
+        .with_max_retries(0)
+}
+
+/// A group as the API describes it.
+pub fn group_json(id: &str) -> Value {
+    json!({
+        "id": id,
+        "name": format!("group {id}"),
+        "type": "private",
+        "description": "",
+        "creator_user_id": "1",
+        "image_url": null,
+        "share_url": null,
+        "created_at": 1_000,
+        "updated_at": 2_000,
+        "members": [
+            {"user_id": "1", "nickname": "alice", "muted": false, "image_url": ""},
+        ],
+    })
+}
+
+/// A message sent by the user `1` as the API describes it.
+pub fn message_json(id: &str, created_at: i64, attachments: Vec<Value>) -> Value {
+    json!({
+        "id": id,
+        "source_guid": id,
+        "created_at": created_at,
+        "user_id": "1",
+        "group_id": "1",
+        "recipient_id": null,
+        "name": "alice",
+        "avatar_url": null,
+        "text": null,
+        "system": false,
+        "favorited_by": [],
+        "attachments": attachments,
+    })
+}
+
+/// A page of `messages` as the API describes it.
+pub fn messages_page(messages: Vec<Value>) -> Value {
+    json!({"count": messages.len(), "messages": messages})
+}
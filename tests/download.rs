@@ -0,0 +1,474 @@
+//! Downloads from a mock GroupMe API, of attachments served by the same mock server.
+
+mod common;
+
+use std::{fs, path::PathBuf};
+
+use chrono::{DateTime, Local, TimeZone, Utc};
+use groupme_download_rust::{
+    cache::Cache,
+    config::Config,
+    download::{self, Conversation, DownloadOptions, DownloadRun},
+    filename::FilenameTz,
+    manifest::{Entry, MANIFEST_FILENAME, Status},
+    model::Group,
+    progress::{self, Progress},
+    resume_state::{self, ResumeState},
+};
+use serde_json::{Value, json};
+
+use common::{MockResponse, MockServer, TempDir, group_json, message_json, messages_page};
+
+/// A test's server, serving group `1`, and the config and client to download from it.
+struct Setup {
+    server: MockServer,
+    dir: TempDir,
+    config: Config,
+}
+
+impl Setup {
+    fn new(name: &str) -> Self {
+        let server = MockServer::start();
+        let dir = TempDir::new(name);
+        let config = common::config(&server, &dir);
+        Self {
+            server,
+            dir,
+            config,
+        }
+    }
+
+    /// Serve the messages of group `id` as a single page, each with one image,
+    /// numbered from 1, sent 100 seconds apart.
+    fn messages(&self, id: &str, images: &[&str]) {
+        let messages = images
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(index, image)| {
+                let id = (index + 1).to_string();
+                message_json(&id, 1_000 + 100 * index as i64, vec![self.image(image)])
+            })
+            .collect();
+        self.server.mock(
+            &format!("/v3/groups/{id}/messages"),
+            &[],
+            MockResponse::api(messages_page(messages)),
+        );
+    }
+
+    /// An image attachment, served at `/media/<name>.jpeg`.
+    fn image(&self, name: &str) -> Value {
+        json!({"type": "image", "url": self.media_url(name)})
+    }
+
+    fn media_url(&self, name: &str) -> String {
+        format!("{}/media/{name}.jpeg", self.server.uri())
+    }
+
+    fn media_requests(&self, name: &str) -> usize {
+        self.server
+            .requests_to(&format!("/media/{name}.jpeg"))
+            .len()
+    }
+
+    /// The directory the files of group `id` are downloaded to.
+    fn group_dir(&self, id: &str) -> PathBuf {
+        self.config.image_dir.join(format!("group {id}_{id}"))
+    }
+
+    fn run(&self, options: DownloadOptions) -> DownloadRun {
+        DownloadRun::new(
+            common::client(&self.dir, &self.config),
+            &self.config,
+            options,
+            Progress::new(&progress::multi_progress(true), false),
+        )
+    }
+
+    async fn download(&self, options: DownloadOptions) -> Vec<Entry> {
+        let client = common::client(&self.dir, &self.config);
+        download::download_group(&client, &self.config, group("1"), date_range(), options)
+            .await
+            .unwrap()
+            .entries
+    }
+}
+
+fn group(id: &str) -> Group {
+    serde_json::from_value(group_json(id)).unwrap()
+}
+
+fn date_range() -> (DateTime<Local>, DateTime<Local>) {
+    (
+        Utc.timestamp_opt(0, 0).unwrap().into(),
+        Utc.timestamp_opt(10_000, 0).unwrap().into(),
+    )
+}
+
+fn statuses(entries: &[Entry]) -> Vec<Status> {
+    entries.iter().map(|entry| entry.status).collect()
+}
+
+/// The names of the files in `dir`, sorted.
+fn filenames(dir: &PathBuf) -> Vec<String> {
+    let mut filenames = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    filenames.sort();
+    filenames
+}
+
+#[tokio::test]
+async fn existing_files_are_skipped() {
+    let setup = Setup::new("skip-existing");
+    setup.messages("1", &["a", "b"]);
+    setup
+        .server
+        .mock("/media/a.jpeg", &[], MockResponse::image(b"a"));
+    setup
+        .server
+        .mock("/media/b.jpeg", &[], MockResponse::image(b"b"));
+
+    let first = setup.download(DownloadOptions::default()).await;
+    let second = setup.download(DownloadOptions::default()).await;
+
+    assert_eq!(statuses(&first), [Status::Downloaded, Status::Downloaded]);
+    assert_eq!(statuses(&second), [Status::Skipped, Status::Skipped]);
+    for (first, second) in first.iter().zip(&second) {
+        assert_eq!(first.path, second.path);
+    }
+    // they are recognized by name, without being requested again
+    assert_eq!(setup.media_requests("a"), 1);
+    assert_eq!(setup.media_requests("b"), 1);
+    let path = first[1].path.as_ref().unwrap();
+    assert!(path.starts_with(setup.group_dir("1")));
+    assert_eq!(fs::read(path).unwrap(), b"a");
+}
+
+#[tokio::test]
+async fn overwrite_downloads_existing_files_again() {
+    let setup = Setup::new("overwrite");
+    setup.messages("1", &["a"]);
+    setup
+        .server
+        .mock("/media/a.jpeg", &[], MockResponse::image(b"new"));
+    let first = setup.download(DownloadOptions::default()).await;
+    let path = first[0].path.clone().unwrap();
+    fs::write(&path, b"old").unwrap();
+
+    let second = setup
+        .download(DownloadOptions {
+            overwrite: true,
+            ..Default::default()
+        })
+        .await;
+
+    assert_eq!(statuses(&second), [Status::Downloaded]);
+    assert_eq!(second[0].path.as_ref(), Some(&path));
+    assert_eq!(fs::read(&path).unwrap(), b"new");
+}
+
+#[tokio::test]
+async fn limit_stops_after_as_many_downloads() {
+    let setup = Setup::new("limit");
+    setup.messages("1", &["a", "b", "c"]);
+    for name in ["a", "b", "c"] {
+        setup.server.mock(
+            &format!("/media/{name}.jpeg"),
+            &[],
+            MockResponse::image(b"x"),
+        );
+    }
+
+    let entries = setup
+        .download(DownloadOptions {
+            limit: Some(2),
+            ..Default::default()
+        })
+        .await;
+
+    // newest first
+    assert_eq!(statuses(&entries), [Status::Downloaded, Status::Downloaded]);
+    assert_eq!(setup.media_requests("c"), 1);
+    assert_eq!(setup.media_requests("b"), 1);
+    assert_eq!(setup.media_requests("a"), 0);
+}
+
+#[tokio::test]
+async fn the_group_image_does_not_count_toward_the_limit() {
+    let setup = Setup::new("limit-group-image");
+    setup.messages("1", &["a"]);
+    setup
+        .server
+        .mock("/media/a.jpeg", &[], MockResponse::image(b"a"));
+    setup
+        .server
+        .mock("/media/group.jpeg", &[], MockResponse::image(b"group"));
+    let group = Group {
+        image_url: Some(setup.media_url("group")),
+        ..group("1")
+    };
+    let client = common::client(&setup.dir, &setup.config);
+
+    let entries = download::download_group(
+        &client,
+        &setup.config,
+        group,
+        date_range(),
+        DownloadOptions {
+            limit: Some(1),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap()
+    .entries;
+
+    assert_eq!(statuses(&entries), [Status::Downloaded, Status::Downloaded]);
+    assert_eq!(entries[1].url, setup.media_url("a"));
+}
+
+#[tokio::test]
+async fn max_total_leaves_the_rest_not_fetched() {
+    let setup = Setup::new("max-total");
+    setup.messages("1", &["a"]);
+    setup.messages("2", &["b"]);
+    setup
+        .server
+        .mock("/media/a.jpeg", &[], MockResponse::image(b"aaaa"));
+    setup
+        .server
+        .mock("/media/b.jpeg", &[], MockResponse::image(b"bbbb"));
+    let run = setup.run(DownloadOptions {
+        max_total: Some(4),
+        ..Default::default()
+    });
+
+    let first = run
+        .download_conversation(
+            Conversation::Group(group("1")),
+            None,
+            None,
+            Some(date_range()),
+        )
+        .await
+        .unwrap();
+    let second = run
+        .download_conversation(
+            Conversation::Group(group("2")),
+            None,
+            None,
+            Some(date_range()),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(statuses(&first), [Status::Downloaded]);
+    assert_eq!(statuses(&second), [Status::NotFetched]);
+    assert_eq!(setup.media_requests("b"), 0);
+    // so that `retry-failures` can download it
+    let failures = run.failures.lock().unwrap();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].entry.url, setup.media_url("b"));
+    assert_eq!(failures[0].entry.status, Status::NotFetched);
+}
+
+#[tokio::test]
+async fn failed_downloads_are_recorded() {
+    let setup = Setup::new("failure");
+    setup.messages("1", &["a"]);
+    setup
+        .server
+        .mock("/media/a.jpeg", &[], MockResponse::new(500, "oops"));
+    let run = setup.run(DownloadOptions::default());
+
+    let entries = run
+        .download_conversation(
+            Conversation::Group(group("1")),
+            None,
+            None,
+            Some(date_range()),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(statuses(&entries), [Status::Failed]);
+    assert!(entries[0].error.as_ref().unwrap().contains("500"));
+    assert_eq!(run.progress.failed(), 1);
+    let failures = run.failures.lock().unwrap();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].entry.url, setup.media_url("a"));
+    assert_eq!(failures[0].provisional_ext.as_deref(), Some("jpeg"));
+    // and in the manifest
+    let manifest = fs::read_to_string(setup.group_dir("1").join(MANIFEST_FILENAME)).unwrap();
+    assert!(manifest.contains("\"failed\""));
+}
+
+#[tokio::test]
+async fn a_download_cut_short_leaves_no_partial_file() {
+    let setup = Setup::new("cut-short");
+    setup.messages("1", &["a"]);
+    setup.server.mock(
+        "/media/a.jpeg",
+        &[],
+        MockResponse::image(b"abc").cut_short(1_000),
+    );
+
+    let entries = setup.download(DownloadOptions::default()).await;
+
+    assert_eq!(statuses(&entries), [Status::Failed]);
+    // neither the file nor its `.part` temporary file is left behind
+    assert_eq!(filenames(&setup.group_dir("1")), [MANIFEST_FILENAME]);
+}
+
+#[tokio::test]
+async fn a_missing_attachment_is_unavailable_rather_than_failed() {
+    let setup = Setup::new("unavailable");
+    setup.messages("1", &["a"]);
+    setup
+        .server
+        .mock("/media/a.jpeg", &[], MockResponse::new(404, ""));
+    let run = setup.run(DownloadOptions::default());
+
+    let entries = run
+        .download_conversation(
+            Conversation::Group(group("1")),
+            None,
+            None,
+            Some(date_range()),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(statuses(&entries), [Status::Unavailable]);
+    assert_eq!(run.progress.failed(), 0);
+    assert!(run.failures.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn filenames_are_rendered_in_the_chosen_time_zone() {
+    let setup = Setup::new("utc");
+    setup.messages("1", &["a"]);
+    setup
+        .server
+        .mock("/media/a.jpeg", &[], MockResponse::image(b"a"));
+
+    let entries = setup
+        .download(DownloadOptions {
+            tz: FilenameTz::Utc,
+            ..Default::default()
+        })
+        .await;
+
+    assert_eq!(
+        entries[0].path.as_ref().unwrap(),
+        &setup
+            .group_dir("1")
+            .join("1970-01-01T00_16_40.1.0.alice.jpeg")
+    );
+}
+
+#[tokio::test]
+async fn continue_on_error_downloads_the_rest() {
+    let setup = Setup::new("continue-on-error");
+    setup.messages("1", &["a", "b"]);
+    setup
+        .server
+        .mock("/media/a.jpeg", &[], MockResponse::image(b"a"));
+    setup
+        .server
+        .mock("/media/b.jpeg", &[], MockResponse::new(500, "oops"));
+    let run = setup.run(DownloadOptions {
+        continue_on_error: true,
+        ..Default::default()
+    });
+
+    let entries = run
+        .download_conversation(
+            Conversation::Group(group("1")),
+            None,
+            None,
+            Some(date_range()),
+        )
+        .await
+        .unwrap();
+
+    let mut statuses = statuses(&entries);
+    statuses.sort_by_key(|status| *status == Status::Failed);
+    assert_eq!(statuses, [Status::Downloaded, Status::Failed]);
+    assert_eq!(setup.media_requests("a"), 1);
+    assert_eq!(run.progress.failed(), 1);
+    assert_eq!(run.failures.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn resume_carries_on_from_the_saved_message() {
+    let setup = Setup::new("resume");
+    setup.server.mock(
+        "/v3/groups/1/messages",
+        &[("before_id", "2")],
+        MockResponse::api(messages_page(vec![message_json(
+            "1",
+            1_000,
+            vec![setup.image("a")],
+        )])),
+    );
+    setup
+        .server
+        .mock("/media/a.jpeg", &[], MockResponse::image(b"a"));
+    let run = setup.run(DownloadOptions::default());
+    let cache = Cache::in_dir(setup.dir.path());
+    let resume = ResumeState {
+        before_id: "2".to_string(),
+        newest: Utc.timestamp_opt(10_000, 0).unwrap(),
+        oldest: Utc.timestamp_opt(0, 0).unwrap(),
+    };
+    resume_state::write(&cache, "1", &resume).unwrap();
+
+    let entries = run
+        .download_conversation(
+            Conversation::Group(group("1")),
+            None,
+            Some(resume),
+            Some(date_range()),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(statuses(&entries), [Status::Downloaded]);
+    let requests = setup.server.requests_to("/v3/groups/1/messages");
+    assert!(
+        requests
+            .iter()
+            .all(|request| request.query("before_id") == Some("2"))
+    );
+    // the download finished, so there is nothing left to resume
+    assert!(resume_state::read(&cache, "1").unwrap().is_none());
+}
+
+#[tokio::test]
+async fn files_saved_under_the_old_default_names_are_skipped() {
+    let setup = Setup::new("legacy-names");
+    setup.messages("1", &["a"]);
+    setup
+        .server
+        .mock("/media/a.jpeg", &[], MockResponse::image(b"a"));
+    let legacy_path = setup
+        .group_dir("1")
+        .join("1970-01-01T00_16_40.0.alice.jpeg");
+    fs::create_dir_all(setup.group_dir("1")).unwrap();
+    fs::write(&legacy_path, b"a").unwrap();
+
+    let entries = setup
+        .download(DownloadOptions {
+            tz: FilenameTz::Utc,
+            ..Default::default()
+        })
+        .await;
+
+    assert_eq!(statuses(&entries), [Status::Skipped]);
+    assert_eq!(entries[0].path.as_ref(), Some(&legacy_path));
+    assert_eq!(setup.media_requests("a"), 0);
+}